@@ -0,0 +1,716 @@
+//! The HTTP client shared by every request to the GitHub and GitLab APIs.
+
+use anyhow::{anyhow, bail, Context as _};
+use log::{info, warn};
+use serde::Deserialize;
+use ureq::{Agent, Proxy, Response};
+use url::Url;
+
+use std::fmt;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+static USER_AGENT: &str = "cargo-scripts <https://github.com/qryxip/cargo-scripts>";
+
+/// Waits for the primary rate limit to be reset only if it takes no longer than this.
+const MAX_RATE_LIMIT_WAIT: Duration = Duration::from_secs(60);
+
+/// The API that a `Client` talks to.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Service {
+    Github,
+    Gitlab,
+}
+
+impl Service {
+    fn name(self) -> &'static str {
+        match self {
+            Self::Github => "GitHub",
+            Self::Gitlab => "GitLab",
+        }
+    }
+
+    fn noun(self) -> &'static str {
+        match self {
+            Self::Github => "gist",
+            Self::Gitlab => "snippet",
+        }
+    }
+
+    fn authorization(self, token: &str) -> String {
+        match self {
+            Self::Github => format!("token {}", token),
+            Self::Gitlab => format!("Bearer {}", token),
+        }
+    }
+}
+
+pub(crate) struct Client {
+    service: Service,
+    agent: Agent,
+    proxy: Option<Proxy>,
+    connect_timeout: Duration,
+    read_timeout: Duration,
+    tls_config: Option<Arc<rustls::ClientConfig>>,
+    retries: u32,
+}
+
+impl Client {
+    /// `proxy` is used only for `https` URLs.
+    pub(crate) fn new(
+        service: Service,
+        connect_timeout: Duration,
+        read_timeout: Duration,
+        proxy: Option<&str>,
+        cainfo: Option<&Path>,
+        retries: u32,
+    ) -> anyhow::Result<Self> {
+        let proxy = proxy
+            .map(|proxy| {
+                Proxy::new(proxy.trim_end_matches('/'))
+                    .map_err(|err| anyhow!("invalid proxy {:?}: {}", proxy, err))
+            })
+            .transpose()?;
+
+        let tls_config = cainfo
+            .map(|cainfo| {
+                let mut tls_config = rustls::ClientConfig::new();
+                tls_config
+                    .root_store
+                    .add_server_trust_anchors(&webpki_roots::TLS_SERVER_ROOTS);
+                let file = File::open(cainfo)
+                    .with_context(|| format!("failed to open {}", cainfo.display()))?;
+                let (_, invalid) = tls_config
+                    .root_store
+                    .add_pem_file(&mut BufReader::new(file))
+                    .map_err(|()| anyhow!("failed to read {}", cainfo.display()))?;
+                if invalid > 0 {
+                    warn!(
+                        "Ignored {} invalid certificate(s) in {}",
+                        invalid,
+                        cainfo.display()
+                    );
+                }
+                Ok::<_, anyhow::Error>(Arc::new(tls_config))
+            })
+            .transpose()?;
+
+        Ok(Self {
+            service,
+            agent: ureq::agent(),
+            proxy,
+            connect_timeout,
+            read_timeout,
+            tls_config,
+            retries,
+        })
+    }
+
+    /// Sends a request, retrying on rate limits and, except for `POST`, on 5xx responses.
+    ///
+    /// A `POST` that failed with 5xx may have created the resource, so it is not sent again.
+    ///
+    /// Network errors are returned as `Err`. Any other response is returned as it is.
+    pub(crate) fn send(
+        &self,
+        method: &str,
+        url: &Url,
+        token: Option<&str>,
+        json: Option<&serde_json::Value>,
+    ) -> anyhow::Result<Response> {
+        self.send_with_headers(method, url, token, json, &[])
+    }
+
+    fn send_with_headers(
+        &self,
+        method: &str,
+        url: &Url,
+        token: Option<&str>,
+        json: Option<&serde_json::Value>,
+        headers: &[(&str, &str)],
+    ) -> anyhow::Result<Response> {
+        for attempt in 1.. {
+            let mut req = self.agent.request(method, url.as_ref());
+            req.set("User-Agent", USER_AGENT)
+                .timeout_connect(self.connect_timeout.as_millis() as u64)
+                .timeout_read(self.read_timeout.as_millis() as u64);
+            if let Some(token) = token {
+                req.set("Authorization", &self.service.authorization(token));
+            }
+            for (header, value) in headers {
+                req.set(header, value);
+            }
+            if let (Some(proxy), "https") = (&self.proxy, url.scheme()) {
+                req.set_proxy(proxy.clone());
+            }
+            if let Some(tls_config) = &self.tls_config {
+                req.set_tls_config(tls_config.clone());
+            }
+            let mut res = match json {
+                Some(json) => req.send_json(json.clone()),
+                None => req.call(),
+            };
+            raise_synthetic_error(&res)?;
+
+            let wait = match rate_limit_reset(&res) {
+                Some(reset) if reset > MAX_RATE_LIMIT_WAIT => bail!(
+                    "the API rate limit is exhausted and will be reset in {} seconds{}",
+                    reset.as_secs(),
+                    if token.is_none() {
+                        format!(
+                            ". configure a {} token to get a higher limit",
+                            self.service.name()
+                        )
+                    } else {
+                        "".to_owned()
+                    },
+                ),
+                Some(reset) => Some(reset),
+                None => {
+                    // A secondary rate limit may be told only in the body.
+                    let (buffered, body) = if res.status() == 403 && !res.has("Retry-After") {
+                        let (res, body) = buffer(res)?;
+                        (res, Some(body))
+                    } else {
+                        (res, None)
+                    };
+                    res = buffered;
+                    retry_wait(method, &res, body.as_deref(), attempt)
+                }
+            };
+
+            match wait {
+                Some(wait) if attempt <= self.retries => {
+                    warn!(
+                        "{} {}. Retrying in {} seconds ({}/{})",
+                        res.status(),
+                        res.status_text(),
+                        wait.as_secs(),
+                        attempt,
+                        self.retries,
+                    );
+                    thread::sleep(wait);
+                    info!("{} {}", method, url);
+                }
+                _ => return Ok(res),
+            }
+        }
+        unreachable!();
+    }
+
+    /// Same as `send` except that a response other than `expected` is returned as `ApiError`.
+    pub(crate) fn send_expecting(
+        &self,
+        method: &str,
+        url: &Url,
+        token: Option<&str>,
+        json: Option<&serde_json::Value>,
+        expected: u16,
+    ) -> anyhow::Result<Response> {
+        let res = self.send(method, url, token, json)?;
+        info!("{} {}", res.status(), res.status_text());
+        if res.status() != expected {
+            return Err(ApiError::new(self.service, method, url, token.is_some(), res).into());
+        }
+        Ok(res)
+    }
+
+    /// Sends `GET` with `If-None-Match: <etag>`. Returns `None` for `304 Not Modified`.
+    ///
+    /// A response other than `200` or `304` is returned as `ApiError`.
+    pub(crate) fn get_if_none_match(
+        &self,
+        url: &Url,
+        token: Option<&str>,
+        etag: Option<&str>,
+    ) -> anyhow::Result<Option<Response>> {
+        let headers = etag.map(|etag| ("If-None-Match", etag));
+        let res = self.send_with_headers("GET", url, token, None, headers.as_slice())?;
+        info!("{} {}", res.status(), res.status_text());
+        match res.status() {
+            200 => Ok(Some(res)),
+            304 if etag.is_some() => Ok(None),
+            _ => Err(ApiError::new(self.service, "GET", url, token.is_some(), res).into()),
+        }
+    }
+}
+
+/// An unexpected response from the API.
+#[derive(Debug)]
+pub(crate) struct ApiError {
+    service: Service,
+    method: String,
+    url: Url,
+    authenticated: bool,
+    status: u16,
+    status_text: String,
+    oauth_scopes: Option<String>,
+    body: ApiErrorBody,
+}
+
+#[derive(Default, Deserialize, Debug)]
+struct ApiErrorBody {
+    #[serde(default)]
+    message: Option<String>,
+    #[serde(default)]
+    documentation_url: Option<String>,
+    #[serde(default)]
+    errors: Vec<serde_json::Value>,
+}
+
+impl ApiError {
+    fn new(service: Service, method: &str, url: &Url, authenticated: bool, res: Response) -> Self {
+        let status = res.status();
+        let status_text = res.status_text().to_owned();
+        let oauth_scopes = res.header("X-OAuth-Scopes").map(ToOwned::to_owned);
+        // Bodies that are not JSON, such as HTML error pages of proxies, are kept as the message.
+        let body = res
+            .into_string()
+            .ok()
+            .map(|body| {
+                serde_json::from_str(&body).unwrap_or_else(|_| ApiErrorBody {
+                    message: Some(body.trim().to_owned()).filter(|s| !s.is_empty()),
+                    ..ApiErrorBody::default()
+                })
+            })
+            .unwrap_or_default();
+        Self {
+            service,
+            method: method.to_owned(),
+            url: url.clone(),
+            authenticated,
+            status,
+            status_text,
+            oauth_scopes,
+            body,
+        }
+    }
+
+    pub(crate) fn status(&self) -> u16 {
+        self.status
+    }
+
+    fn hint(&self) -> Option<String> {
+        let (name, noun) = (self.service.name(), self.service.noun());
+        let lacks_gist_scope = self.service == Service::Github
+            && self
+                .oauth_scopes
+                .as_ref()
+                .is_some_and(|scopes| !scopes.split(',').any(|scope| scope.trim() == "gist"));
+        match (self.service, self.status) {
+            (_, 401) => Some(format!(
+                "the {} token is invalid or expired. set a new one with `cargo scripts config set \
+                 {}-token`",
+                name,
+                name.to_lowercase(),
+            )),
+            (_, 403) | (_, 404) if lacks_gist_scope => {
+                Some("the GitHub token does not have the `gist` scope".to_owned())
+            }
+            (_, 403) => Some(format!("the {} token is not allowed to do this", name)),
+            (_, 404) if !self.authenticated => Some(format!(
+                "check the {} ID. if the {} is not public, configure a {} token and do not use \
+                 `--no-auth`",
+                noun, noun, name,
+            )),
+            (_, 404) if self.method == "GET" => Some(format!("check the {} ID", noun)),
+            (_, 404) => Some(format!(
+                "check the {} ID and that the {} is owned by the {} token's user",
+                noun, noun, name,
+            )),
+            (_, 500..=599) if self.method == "POST" => Some(format!(
+                "the {} may have been created anyway. check `cargo scripts gist list` before trying again",
+                noun,
+            )),
+            (Service::Github, 422) | (Service::Gitlab, 400) => Some(format!(
+                "the {} was rejected. note that empty files are not allowed",
+                noun,
+            )),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} {}: {} {}",
+            self.method, self.url, self.status, self.status_text,
+        )?;
+        if let Some(message) = &self.body.message {
+            write!(f, ": {:?}", message)?;
+        }
+        for error in &self.body.errors {
+            write!(f, " {}", error)?;
+        }
+        if let Some(hint) = self.hint() {
+            write!(f, " ({})", hint)?;
+        }
+        if let Some(documentation_url) = &self.body.documentation_url {
+            write!(f, " <{}>", documentation_url)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for ApiError {}
+
+impl fmt::Debug for Client {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Client")
+            .field("service", &self.service)
+            .field("proxy", &self.proxy)
+            .field("connect_timeout", &self.connect_timeout)
+            .field("read_timeout", &self.read_timeout)
+            .field("tls_config", &self.tls_config.as_ref().map(|_| ".."))
+            .field("retries", &self.retries)
+            .finish()
+    }
+}
+
+/// Returns the time until `X-RateLimit-Reset` if the primary rate limit is exhausted.
+fn rate_limit_reset(res: &Response) -> Option<Duration> {
+    if ![403, 429].contains(&res.status()) || res.header("X-RateLimit-Remaining") != Some("0") {
+        return None;
+    }
+    let reset = res.header("X-RateLimit-Reset")?.parse::<u64>().ok()?;
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).ok()?.as_secs();
+    Some(Duration::from_secs(reset.saturating_sub(now) + 1))
+}
+
+/// How long to wait before sending the request again, or `None` if it should not be retried. The
+/// primary rate limit is handled by `rate_limit_reset`.
+///
+/// `body` is needed only for `403` without `Retry-After`.
+fn retry_wait(method: &str, res: &Response, body: Option<&str>, attempt: u32) -> Option<Duration> {
+    if !(res.status() >= 500 && is_idempotent(method) || is_secondary_rate_limit(res, body)) {
+        return None;
+    }
+    let backoff = Duration::from_secs(1 << attempt.min(6));
+    let retry_after = res
+        .header("Retry-After")
+        .and_then(|s| s.parse().ok())
+        .map(Duration::from_secs);
+    Some(retry_after.unwrap_or(backoff))
+}
+
+/// Whether a request can be sent again after a 5xx. The `PATCH`es of this crate send whole files,
+/// so repeating them has no further effect.
+fn is_idempotent(method: &str) -> bool {
+    matches!(method, "GET" | "DELETE" | "PATCH")
+}
+
+/// GitHub does not always send `Retry-After` for the secondary rate limit, but it says so in the
+/// message.
+fn is_secondary_rate_limit(res: &Response, body: Option<&str>) -> bool {
+    res.status() == 429
+        || res.status() == 403
+            && (res.has("Retry-After")
+                || body.is_some_and(|body| body.to_lowercase().contains("secondary rate limit")))
+}
+
+/// Reads the body of `res` and returns a `Response` with the same status, headers and body.
+fn buffer(res: Response) -> anyhow::Result<(Response, String)> {
+    let mut head = res.status_line().to_owned();
+    for name in res.headers_names() {
+        // The body is no longer encoded.
+        if ["content-length", "transfer-encoding"].contains(&&*name.to_lowercase()) {
+            continue;
+        }
+        for value in res.all(&name) {
+            head += &format!("\r\n{}: {}", name, value);
+        }
+    }
+    let body = res.into_string()?;
+    let res = format!("{}\r\n\r\n{}", head, body).parse()?;
+    Ok((res, body))
+}
+
+fn raise_synthetic_error(res: &Response) -> anyhow::Result<()> {
+    if let Some(err) = res.synthetic_error() {
+        let mut err = err as &dyn std::error::Error;
+        let mut displays = vec![err.to_string()];
+        while let Some(source) = err.source() {
+            displays.push(source.to_string());
+            err = source;
+        }
+        let mut displays = displays.into_iter().rev();
+        let cause = anyhow!("{}", displays.next().unwrap());
+        return Err(displays.fold(cause, |err, display| err.context(display)));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{buffer, rate_limit_reset, retry_wait, ApiError, Service};
+
+    use ureq::Response;
+    use url::Url;
+
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+    #[test]
+    fn primary_rate_limit() {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let reset = |status: u16, remaining: &str, reset: &str| {
+            rate_limit_reset(&response(
+                status,
+                &[
+                    ("X-RateLimit-Remaining", remaining),
+                    ("X-RateLimit-Reset", reset),
+                ],
+                "",
+            ))
+        };
+
+        let wait = reset(403, "0", &(now + 30).to_string()).unwrap();
+        assert!(
+            (Duration::from_secs(29)..=Duration::from_secs(31)).contains(&wait),
+            "{:?}",
+            wait,
+        );
+        assert_eq!(
+            reset(429, "0", &(now - 10).to_string()),
+            Some(Duration::from_secs(1)),
+        );
+        assert_eq!(reset(403, "1", &(now + 30).to_string()), None);
+        assert_eq!(reset(500, "0", &(now + 30).to_string()), None);
+        assert_eq!(reset(403, "0", "x"), None);
+        assert_eq!(rate_limit_reset(&response(403, &[], "")), None);
+    }
+
+    #[test]
+    fn retry() {
+        let secs = Duration::from_secs;
+        let wait = |method: &str, status: u16, headers: &[(&str, &str)], body: Option<&str>| {
+            retry_wait(method, &response(status, headers, ""), body, 1)
+        };
+
+        // 5xx is retried only for idempotent requests.
+        assert_eq!(wait("GET", 502, &[], None), Some(secs(2)));
+        assert_eq!(
+            wait("PATCH", 503, &[("Retry-After", "3")], None),
+            Some(secs(3))
+        );
+        assert_eq!(wait("DELETE", 500, &[], None), Some(secs(2)));
+        assert_eq!(wait("POST", 500, &[], None), None);
+        assert_eq!(wait("POST", 503, &[("Retry-After", "3")], None), None);
+
+        // The secondary rate limit is retried for every method.
+        assert_eq!(wait("POST", 429, &[], None), Some(secs(2)));
+        assert_eq!(
+            wait("POST", 403, &[("Retry-After", "5")], None),
+            Some(secs(5))
+        );
+        let message = r#"{"message":"You have exceeded a secondary rate limit."}"#;
+        assert_eq!(wait("POST", 403, &[], Some(message)), Some(secs(2)));
+        assert_eq!(
+            wait("GET", 403, &[], Some(r#"{"message":"Forbidden"}"#)),
+            None
+        );
+        assert_eq!(wait("GET", 403, &[], None), None);
+
+        assert_eq!(wait("GET", 404, &[], None), None);
+        assert_eq!(wait("GET", 200, &[], None), None);
+
+        let res = response(500, &[], "");
+        assert_eq!(retry_wait("GET", &res, None, 3), Some(secs(8)));
+        assert_eq!(retry_wait("GET", &res, None, 10), Some(secs(64)));
+    }
+
+    #[test]
+    fn buffer_keeps_the_response() -> anyhow::Result<()> {
+        let res = response(
+            403,
+            &[("Retry-After", "5"), ("X-OAuth-Scopes", "gist")],
+            "body",
+        );
+        let (res, body) = buffer(res)?;
+        assert_eq!(body, "body");
+        assert_eq!(res.status(), 403);
+        assert_eq!(res.status_text(), "Status");
+        assert_eq!(res.header("Retry-After"), Some("5"));
+        assert_eq!(res.header("X-OAuth-Scopes"), Some("gist"));
+        assert_eq!(res.into_string()?, "body");
+        Ok(())
+    }
+
+    #[test]
+    fn api_error_hint() {
+        let hint = |service: Service,
+                    method: &str,
+                    authenticated: bool,
+                    status: u16,
+                    headers: &[(&str, &str)]| {
+            api_error(
+                service,
+                method,
+                authenticated,
+                response(status, headers, "{}"),
+            )
+            .hint()
+        };
+        let github = Service::Github;
+        let gitlab = Service::Gitlab;
+
+        for &(service, method, authenticated, status, headers, expected) in &[
+            (
+                github,
+                "GET",
+                true,
+                401,
+                &[][..],
+                Some(
+                    "the GitHub token is invalid or expired. set a new one with `cargo scripts \
+                     config set github-token`",
+                ),
+            ),
+            (
+                gitlab,
+                "GET",
+                true,
+                401,
+                &[],
+                Some(
+                    "the GitLab token is invalid or expired. set a new one with `cargo scripts \
+                     config set gitlab-token`",
+                ),
+            ),
+            (
+                github,
+                "PATCH",
+                true,
+                404,
+                &[("X-OAuth-Scopes", "repo, user")],
+                Some("the GitHub token does not have the `gist` scope"),
+            ),
+            (
+                github,
+                "PATCH",
+                true,
+                403,
+                &[("X-OAuth-Scopes", "repo, gist")],
+                Some("the GitHub token is not allowed to do this"),
+            ),
+            (
+                gitlab,
+                "PUT",
+                true,
+                403,
+                &[("X-OAuth-Scopes", "repo")],
+                Some("the GitLab token is not allowed to do this"),
+            ),
+            (
+                github,
+                "GET",
+                false,
+                404,
+                &[],
+                Some(
+                    "check the gist ID. if the gist is not public, configure a GitHub token and \
+                     do not use `--no-auth`",
+                ),
+            ),
+            (gitlab, "GET", true, 404, &[], Some("check the snippet ID")),
+            (
+                github,
+                "DELETE",
+                true,
+                404,
+                &[("X-OAuth-Scopes", "gist")],
+                Some("check the gist ID and that the gist is owned by the GitHub token's user"),
+            ),
+            (
+                github,
+                "POST",
+                true,
+                502,
+                &[],
+                Some(
+                    "the gist may have been created anyway. check `cargo scripts gist list` \
+                     before trying again",
+                ),
+            ),
+            (github, "GET", true, 502, &[], None),
+            (
+                github,
+                "POST",
+                true,
+                422,
+                &[],
+                Some("the gist was rejected. note that empty files are not allowed"),
+            ),
+            (
+                gitlab,
+                "POST",
+                true,
+                400,
+                &[],
+                Some("the snippet was rejected. note that empty files are not allowed"),
+            ),
+            (github, "POST", true, 400, &[], None),
+            (gitlab, "POST", true, 422, &[], None),
+        ] {
+            assert_eq!(
+                hint(service, method, authenticated, status, headers).as_deref(),
+                expected,
+                "{:?} {} {}",
+                service,
+                method,
+                status,
+            );
+        }
+    }
+
+    #[test]
+    fn api_error_body() {
+        let display = |body: &str| {
+            api_error(Service::Github, "GET", true, response(502, &[], body)).to_string()
+        };
+
+        assert_eq!(
+            display(
+                r#"{"message":"Validation Failed","errors":[{"code":"missing"}],"documentation_url":"https://docs.github.com"}"#,
+            ),
+            "GET https://api.github.com/gists/x: 502 Status: \"Validation Failed\" \
+             {\"code\":\"missing\"} <https://docs.github.com>",
+        );
+        // Not JSON, such as an error page of a proxy.
+        assert_eq!(
+            display("\n<html>Bad Gateway</html>\n"),
+            "GET https://api.github.com/gists/x: 502 Status: \"<html>Bad Gateway</html>\"",
+        );
+        assert_eq!(
+            display(""),
+            "GET https://api.github.com/gists/x: 502 Status"
+        );
+        assert_eq!(
+            display("  \n"),
+            "GET https://api.github.com/gists/x: 502 Status"
+        );
+    }
+
+    fn api_error(service: Service, method: &str, authenticated: bool, res: Response) -> ApiError {
+        let url = "https://api.github.com/gists/x".parse::<Url>().unwrap();
+        ApiError::new(service, method, &url, authenticated, res)
+    }
+
+    fn response(status: u16, headers: &[(&str, &str)], body: &str) -> Response {
+        let mut res = format!("HTTP/1.1 {} Status\r\n", status);
+        for (name, value) in headers {
+            res += &format!("{}: {}\r\n", name, value);
+        }
+        res += "\r\n";
+        res += body;
+        res.parse().unwrap()
+    }
+}
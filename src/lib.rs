@@ -1,8 +1,13 @@
 #![warn(rust_2018_idioms)]
 
+mod http;
+mod modules;
+mod remote;
+
+use crate::remote::{is_not_found, is_rust, remote_filename, Pushed, Remote, RemoteScript};
+
 use anyhow::{anyhow, bail, ensure, Context as _};
 use cargo_metadata::{Package, Resolve, Target};
-use if_chain::if_chain;
 use ignore::WalkBuilder;
 use indexmap::IndexMap;
 use itertools::Itertools as _;
@@ -10,23 +15,24 @@ use log::{info, warn, Level, LevelFilter, Log, Record};
 use once_cell::sync::OnceCell;
 use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
-use serde_json::json;
 use structopt::clap::AppSettings;
 use structopt::StructOpt;
 use strum::{EnumString, EnumVariantNames, IntoStaticStr, VariantNames as _};
-use syn::{Lit, Meta, MetaNameValue};
 use termcolor::{BufferedStandardStream, Color, ColorSpec, WriteColor};
-use ureq::Response;
 use url::Url;
 
 use std::borrow::Cow;
-use std::collections::BTreeMap;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet};
 use std::ffi::{OsStr, OsString};
 use std::io::{self, Read, Stdout, Write};
+use std::num::NonZeroUsize;
 use std::ops::Range;
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{self, AtomicUsize};
 use std::sync::{Arc, Mutex};
-use std::{env, fs, iter};
+use std::time::Duration;
+use std::{env, fs, iter, thread};
 
 #[derive(StructOpt, Debug)]
 #[structopt(
@@ -63,6 +69,9 @@ pub enum OptScripts {
     /// Export a package as a script (in the same format as `cargo-script`)
     #[structopt(author)]
     Export(OptScriptsExport),
+    /// Rewrite the embedded manifest of a script in another format
+    #[structopt(author)]
+    Convert(OptScriptsConvert),
     /// Gist
     #[structopt(author)]
     Gist(OptScriptsGist),
@@ -128,6 +137,12 @@ pub struct OptScriptsRm {
     /// Dry run
     #[structopt(long)]
     pub dry_run: bool,
+    /// Also delete the gist in `gist_ids`
+    #[structopt(long)]
+    pub delete_gist: bool,
+    /// Read the token from stdin
+    #[structopt(long, requires("delete-gist"))]
+    pub token_stdin: bool,
     /// The **name** of the package to remove
     pub package: String,
 }
@@ -191,6 +206,9 @@ pub struct OptScriptsImport {
     /// Path to create the package, defaults to `<workspace-root>/<package-name>`
     #[structopt(long)]
     pub path: Option<PathBuf>,
+    /// Move the inline modules into files in `src`
+    #[structopt(long)]
+    pub split_modules: bool,
     /// Path to the script
     pub file: Option<PathBuf>,
 }
@@ -208,10 +226,37 @@ pub struct OptScriptsExport {
         default_value("auto")
     )]
     pub color: AnsiColorChoice,
+    /// Syntax of the embedded manifest, defaults to `export_format` in the config, then the one in
+    /// the source file
+    #[structopt(long, value_name("FORMAT"), possible_values(ManifestFormat::VARIANTS))]
+    pub format: Option<ManifestFormat>,
+    /// Inline the modules in other files
+    #[structopt(long)]
+    pub bundle: bool,
     /// The **name** of the package to export
     pub package: String,
 }
 
+#[derive(StructOpt, Debug)]
+pub struct OptScriptsConvert {
+    /// Coloring
+    #[structopt(
+        long,
+        value_name("WHEN"),
+        possible_values(AnsiColorChoice::VARIANTS),
+        default_value("auto")
+    )]
+    pub color: AnsiColorChoice,
+    /// Dry run
+    #[structopt(long)]
+    pub dry_run: bool,
+    /// Format to convert to
+    #[structopt(long, value_name("FORMAT"), possible_values(ManifestFormat::VARIANTS))]
+    pub to: ManifestFormat,
+    /// Path to the script
+    pub file: PathBuf,
+}
+
 #[derive(StructOpt, Debug)]
 pub enum OptScriptsGist {
     /// Clone a script from Gist
@@ -223,6 +268,18 @@ pub enum OptScriptsGist {
     /// Pull a script to Gist
     #[structopt(author)]
     Push(OptScriptsGistPush),
+    /// Show the sync state of every script in `gist_ids`
+    #[structopt(author)]
+    Status(OptScriptsGistStatus),
+    /// List your gists
+    #[structopt(author)]
+    List(OptScriptsGistList),
+    /// Show the revisions of a gist
+    #[structopt(author)]
+    Log(OptScriptsGistLog),
+    /// Delete a gist and remove it from `gist_ids`
+    #[structopt(author)]
+    Delete(OptScriptsGistDelete),
 }
 
 #[derive(StructOpt, Debug)]
@@ -244,7 +301,27 @@ pub struct OptScriptsGistClone {
     /// Path to create the package, defaults to `<workspace-root>/<package-name>`
     #[structopt(long)]
     pub path: Option<PathBuf>,
-    /// Gist ID
+    /// Move the inline modules into files in `src`
+    #[structopt(long)]
+    pub split_modules: bool,
+    /// Do not send the token
+    #[structopt(long)]
+    pub no_auth: bool,
+    /// Read the token from stdin
+    #[structopt(long, conflicts_with("no-auth"))]
+    pub token_stdin: bool,
+    /// Use the cached gist instead of accessing the network
+    #[structopt(long)]
+    pub offline: bool,
+    /// Remote backend
+    #[structopt(
+        long,
+        value_name("BACKEND"),
+        possible_values(Backend::VARIANTS),
+        default_value("github")
+    )]
+    pub backend: Backend,
+    /// Gist ID, optionally followed by `@<revision>`
     pub gist_id: String,
 }
 
@@ -264,8 +341,32 @@ pub struct OptScriptsGistPull {
     /// Dry run
     #[structopt(long)]
     pub dry_run: bool,
-    /// The **name** of the package to export
-    pub package: String,
+    /// On conflict, take the remote version of the conflicting hunks, or of the whole file if no
+    /// merge base is available
+    #[structopt(long, conflicts_with("ours"))]
+    pub theirs: bool,
+    /// On conflict, keep the local version of the conflicting hunks, or of the whole file if no
+    /// merge base is available
+    #[structopt(long)]
+    pub ours: bool,
+    /// Do not send the token
+    #[structopt(long)]
+    pub no_auth: bool,
+    /// Read the token from stdin
+    #[structopt(long, conflicts_with("no-auth"))]
+    pub token_stdin: bool,
+    /// Pull the specified revision instead of the latest one
+    #[structopt(long, value_name("SHA"), conflicts_with("all"))]
+    pub rev: Option<String>,
+    /// Pull all the packages in `gist_ids`
+    #[structopt(long)]
+    pub all: bool,
+    /// Number of packages to process in parallel
+    #[structopt(short, long, value_name("N"), default_value("4"))]
+    pub jobs: NonZeroUsize,
+    /// The **name**s of the packages to pull
+    #[structopt(required_unless("all"), conflicts_with("all"))]
+    pub packages: Vec<String>,
 }
 
 #[derive(StructOpt, Debug)]
@@ -290,10 +391,128 @@ pub struct OptScriptsGistPush {
     /// Make the gist private when `--set-upstream` is enabled
     #[structopt(long)]
     pub private: bool,
+    /// Remote backend to create the gist on when `--set-upstream` is enabled
+    #[structopt(
+        long,
+        value_name("BACKEND"),
+        possible_values(Backend::VARIANTS),
+        default_value("github")
+    )]
+    pub backend: Backend,
     /// Set the description of the gist
     #[structopt(long)]
     pub description: Option<String>,
-    /// The **name** of the package to push
+    /// Overwrite the gist even if it has been modified since the last sync
+    #[structopt(short, long)]
+    pub force: bool,
+    /// Read the token from stdin
+    #[structopt(long)]
+    pub token_stdin: bool,
+    /// Push all the packages in `gist_ids`
+    #[structopt(long)]
+    pub all: bool,
+    /// Number of packages to process in parallel
+    #[structopt(short, long, value_name("N"), default_value("4"))]
+    pub jobs: NonZeroUsize,
+    /// The **name**s of the packages to push
+    #[structopt(required_unless("all"), conflicts_with("all"))]
+    pub packages: Vec<String>,
+}
+
+#[derive(StructOpt, Debug)]
+pub struct OptScriptsGistStatus {
+    /// [cargo] Path to Cargo.toml
+    #[structopt(long, value_name("PATH"))]
+    pub manifest_path: Option<PathBuf>,
+    /// [cargo] Coloring
+    #[structopt(
+        long,
+        value_name("WHEN"),
+        possible_values(AnsiColorChoice::VARIANTS),
+        default_value("auto")
+    )]
+    pub color: AnsiColorChoice,
+    /// Output in JSON
+    #[structopt(long)]
+    pub json: bool,
+    /// Use the cached gists instead of accessing the network
+    #[structopt(long)]
+    pub offline: bool,
+    /// Read the token from stdin
+    #[structopt(long)]
+    pub token_stdin: bool,
+}
+
+#[derive(StructOpt, Debug)]
+pub struct OptScriptsGistList {
+    /// [cargo] Path to Cargo.toml
+    #[structopt(long, value_name("PATH"))]
+    pub manifest_path: Option<PathBuf>,
+    /// [cargo] Coloring
+    #[structopt(
+        long,
+        value_name("WHEN"),
+        possible_values(AnsiColorChoice::VARIANTS),
+        default_value("auto")
+    )]
+    pub color: AnsiColorChoice,
+    /// Remote backend
+    #[structopt(
+        long,
+        value_name("BACKEND"),
+        possible_values(Backend::VARIANTS),
+        default_value("github")
+    )]
+    pub backend: Backend,
+    /// Read the token from stdin
+    #[structopt(long)]
+    pub token_stdin: bool,
+    /// Do not fetch the gists that are not cloned to find the ones with a manifest. Such gists are
+    /// shown with `?`
+    #[structopt(long)]
+    pub no_check_manifests: bool,
+}
+
+#[derive(StructOpt, Debug)]
+pub struct OptScriptsGistLog {
+    /// [cargo] Path to Cargo.toml
+    #[structopt(long, value_name("PATH"))]
+    pub manifest_path: Option<PathBuf>,
+    /// [cargo] Coloring
+    #[structopt(
+        long,
+        value_name("WHEN"),
+        possible_values(AnsiColorChoice::VARIANTS),
+        default_value("auto")
+    )]
+    pub color: AnsiColorChoice,
+    /// Read the token from stdin
+    #[structopt(long)]
+    pub token_stdin: bool,
+    /// The **name** of the package
+    pub package: String,
+}
+
+#[derive(StructOpt, Debug)]
+pub struct OptScriptsGistDelete {
+    /// [cargo] Path to Cargo.toml
+    #[structopt(long, value_name("PATH"))]
+    pub manifest_path: Option<PathBuf>,
+    /// [cargo] Coloring
+    #[structopt(
+        long,
+        value_name("WHEN"),
+        possible_values(AnsiColorChoice::VARIANTS),
+        default_value("auto")
+    )]
+    pub color: AnsiColorChoice,
+    /// Dry run
+    #[structopt(long)]
+    pub dry_run: bool,
+    /// Read the token from stdin
+    #[structopt(long)]
+    pub token_stdin: bool,
+    /// The **name** of the package
     pub package: String,
 }
 
@@ -311,6 +530,10 @@ pub enum OptScriptsConfigSet {
     Base(OptScriptsConfigSetBase),
     /// Set `gist_id.<package>`
     GistId(OptScriptsConfigSetGistId),
+    /// Set `github_token`
+    GithubToken(OptScriptsConfigSetGithubToken),
+    /// Set `gitlab.token`
+    GitlabToken(OptScriptsConfigSetGithubToken),
 }
 
 #[derive(StructOpt, Debug)]
@@ -349,12 +572,92 @@ pub struct OptScriptsConfigSetGistId {
     /// Dry run
     #[structopt(long)]
     pub dry_run: bool,
+    /// Remote backend
+    #[structopt(
+        long,
+        value_name("BACKEND"),
+        possible_values(Backend::VARIANTS),
+        default_value("github")
+    )]
+    pub backend: Backend,
     /// Key (Package **name**)
     pub package: String,
     /// Value
     pub gist_id: String,
 }
 
+#[derive(StructOpt, Debug)]
+pub enum OptScriptsConfigSetGithubToken {
+    /// Read the token from a file
+    File(OptScriptsConfigSetGithubTokenFile),
+    /// Read the token from an environment variable
+    Env(OptScriptsConfigSetGithubTokenEnv),
+    /// Read the token from the stdout of a command
+    Command(OptScriptsConfigSetGithubTokenCommand),
+}
+
+#[derive(StructOpt, Debug)]
+pub struct OptScriptsConfigSetGithubTokenFile {
+    /// [cargo] Path to Cargo.toml
+    #[structopt(long, value_name("PATH"))]
+    pub manifest_path: Option<PathBuf>,
+    /// [cargo] Coloring
+    #[structopt(
+        long,
+        value_name("WHEN"),
+        possible_values(AnsiColorChoice::VARIANTS),
+        default_value("auto")
+    )]
+    pub color: AnsiColorChoice,
+    /// Dry run
+    #[structopt(long)]
+    pub dry_run: bool,
+    /// Path to the file
+    pub path: String,
+}
+
+#[derive(StructOpt, Debug)]
+pub struct OptScriptsConfigSetGithubTokenEnv {
+    /// [cargo] Path to Cargo.toml
+    #[structopt(long, value_name("PATH"))]
+    pub manifest_path: Option<PathBuf>,
+    /// [cargo] Coloring
+    #[structopt(
+        long,
+        value_name("WHEN"),
+        possible_values(AnsiColorChoice::VARIANTS),
+        default_value("auto")
+    )]
+    pub color: AnsiColorChoice,
+    /// Dry run
+    #[structopt(long)]
+    pub dry_run: bool,
+    /// Name of the environment variable
+    pub name: String,
+}
+
+#[derive(StructOpt, Debug)]
+#[structopt(setting(AppSettings::TrailingVarArg))]
+pub struct OptScriptsConfigSetGithubTokenCommand {
+    /// [cargo] Path to Cargo.toml
+    #[structopt(long, value_name("PATH"))]
+    pub manifest_path: Option<PathBuf>,
+    /// [cargo] Coloring
+    #[structopt(
+        long,
+        value_name("WHEN"),
+        possible_values(AnsiColorChoice::VARIANTS),
+        default_value("auto")
+    )]
+    pub color: AnsiColorChoice,
+    /// Dry run
+    #[structopt(long)]
+    pub dry_run: bool,
+    /// Command that prints the token
+    #[structopt(required(true), allow_hyphen_values(true))]
+    pub argv: Vec<String>,
+}
+
 #[derive(StructOpt, Debug)]
 pub enum OptScriptsConfigRemove {
     /// Remove `gist_id.<package>`
@@ -443,8 +746,7 @@ impl Context<Stdout, fn() -> io::Result<String>, fn(&str) -> io::Result<String>>
 
             #[cfg(not(windows))]
             fn should_enable_for_stderr() -> bool {
-                atty::is(atty::Stream::Stderr)
-                    && env::var("TERM").ok().map_or(false, |v| v != "dumb")
+                atty::is(atty::Stream::Stderr) && env::var("TERM").ok().is_some_and(|v| v != "dumb")
             }
 
             #[cfg(windows)]
@@ -479,6 +781,17 @@ impl Context<Stdout, fn() -> io::Result<String>, fn(&str) -> io::Result<String>>
 
                 fn log(&self, record: &Record<'_>) {
                     if self.enabled(record.metadata()) {
+                        let captured = CAPTURED_LOGS.with(|captured| {
+                            captured
+                                .borrow_mut()
+                                .as_mut()
+                                .map(|c| c.push((record.level(), record.args().to_string())))
+                                .is_some()
+                        });
+                        if captured {
+                            return;
+                        }
+
                         let mut wtr = self.wtr.lock().unwrap();
                         let (header_fg, header) = match record.level() {
                             Level::Trace => (Color::Magenta, "trace:"),
@@ -508,6 +821,19 @@ impl Context<Stdout, fn() -> io::Result<String>, fn(&str) -> io::Result<String>>
     }
 }
 
+thread_local! {
+    /// The log records of the current thread while `capture_logs` is running.
+    static CAPTURED_LOGS: RefCell<Option<Vec<(Level, String)>>> = const { RefCell::new(None) };
+}
+
+/// Runs `f`, collecting the log records of the current thread instead of printing them.
+fn capture_logs<T>(f: impl FnOnce() -> T) -> (T, Vec<(Level, String)>) {
+    CAPTURED_LOGS.with(|captured| *captured.borrow_mut() = Some(vec![]));
+    let ret = f();
+    let logs = CAPTURED_LOGS.with(|captured| captured.borrow_mut().take().unwrap_or_default());
+    (ret, logs)
+}
+
 #[derive(EnumString, EnumVariantNames, IntoStaticStr, Debug, Clone, Copy)]
 #[strum(serialize_all = "kebab-case")]
 pub enum AnsiColorChoice {
@@ -516,6 +842,45 @@ pub enum AnsiColorChoice {
     Never,
 }
 
+/// The syntax of the manifest embedded in a script.
+#[derive(
+    Deserialize,
+    Serialize,
+    EnumString,
+    EnumVariantNames,
+    IntoStaticStr,
+    Debug,
+    Clone,
+    Copy,
+    PartialEq,
+)]
+#[serde(rename_all = "kebab-case")]
+#[strum(serialize_all = "kebab-case")]
+pub enum ManifestFormat {
+    /// A ```` ```cargo ```` code block in the inner doc comment
+    DocComment,
+    /// A `---cargo` frontmatter block, which `cargo -Zscript` reads
+    Frontmatter,
+    /// A `// cargo-deps: <name>="<version>", ...` line, which `cargo-script` and `rust-script`
+    /// also accept
+    ShortDeps,
+}
+
+impl ManifestFormat {
+    /// Frontmatter, then a `cargo` code block, then a `// cargo-deps:` line.
+    fn detect(code: &str) -> Option<Self> {
+        if Frontmatter::find(code).is_some() {
+            Some(Self::Frontmatter)
+        } else if CargoLangCode::parse(code).ok().flatten().is_some() {
+            Some(Self::DocComment)
+        } else if ShortDeps::find(code).is_some() {
+            Some(Self::ShortDeps)
+        } else {
+            None
+        }
+    }
+}
+
 pub fn run<W: Write, I: FnOnce() -> io::Result<String>, P: FnMut(&str) -> io::Result<String>>(
     opt: Opt,
     ctx: Context<W, I, P>,
@@ -528,15 +893,26 @@ pub fn run<W: Write, I: FnOnce() -> io::Result<String>, P: FnMut(&str) -> io::Re
         Opt::Scripts(OptScripts::Exclude(opt)) => exclude(opt, ctx),
         Opt::Scripts(OptScripts::Import(opt)) => import(opt, ctx),
         Opt::Scripts(OptScripts::Export(opt)) => export(opt, ctx),
+        Opt::Scripts(OptScripts::Convert(opt)) => convert(opt, ctx),
         Opt::Scripts(OptScripts::Gist(OptScriptsGist::Clone(opt))) => gist_clone(opt, ctx),
         Opt::Scripts(OptScripts::Gist(OptScriptsGist::Pull(opt))) => gist_pull(opt, ctx),
         Opt::Scripts(OptScripts::Gist(OptScriptsGist::Push(opt))) => gist_push(opt, ctx),
+        Opt::Scripts(OptScripts::Gist(OptScriptsGist::Status(opt))) => gist_status(opt, ctx),
+        Opt::Scripts(OptScripts::Gist(OptScriptsGist::List(opt))) => gist_list(opt, ctx),
+        Opt::Scripts(OptScripts::Gist(OptScriptsGist::Log(opt))) => gist_log(opt, ctx),
+        Opt::Scripts(OptScripts::Gist(OptScriptsGist::Delete(opt))) => gist_delete(opt, ctx),
         Opt::Scripts(OptScripts::Config(OptScriptsConfig::Set(OptScriptsConfigSet::Base(opt)))) => {
             config_set_base(opt, ctx)
         }
         Opt::Scripts(OptScripts::Config(OptScriptsConfig::Set(OptScriptsConfigSet::GistId(
             opt,
         )))) => config_set_gist_id(opt, ctx),
+        Opt::Scripts(OptScripts::Config(OptScriptsConfig::Set(
+            OptScriptsConfigSet::GithubToken(opt),
+        ))) => config_set_token(Backend::Github, opt, ctx),
+        Opt::Scripts(OptScripts::Config(OptScriptsConfig::Set(
+            OptScriptsConfigSet::GitlabToken(opt),
+        ))) => config_set_token(Backend::Gitlab, opt, ctx),
         Opt::Scripts(OptScripts::Config(OptScriptsConfig::Remove(
             OptScriptsConfigRemove::GistId(opt),
         ))) => config_remove_gist_id(opt, ctx),
@@ -644,9 +1020,7 @@ fn new(opt: OptScriptsNew, ctx: Context<impl Sized, impl Sized, impl Sized>) ->
         cargo_metadata_no_deps_expecting_virtual(manifest_path.as_deref(), color, &cwd)?;
 
     let path = cwd.join(path.strip_prefix(".").unwrap_or(&path));
-    let CargoScriptsConfig { base, .. } = CargoScriptsConfig::load(&workspace_root)?;
-    let base = Path::new(&base);
-    let base = workspace_root.join(base.strip_prefix(".").unwrap_or(base));
+    let base = CargoScriptsConfig::load(&workspace_root)?.base_dir();
 
     for entry in WalkBuilder::new(&base).hidden(false).build() {
         match entry {
@@ -689,16 +1063,30 @@ fn new(opt: OptScriptsNew, ctx: Context<impl Sized, impl Sized, impl Sized>) ->
     )
 }
 
-fn rm(opt: OptScriptsRm, ctx: Context<impl Sized, impl Sized, impl Sized>) -> anyhow::Result<()> {
+fn rm(
+    opt: OptScriptsRm,
+    ctx: Context<
+        impl Sized,
+        impl FnOnce() -> io::Result<String>,
+        impl FnMut(&str) -> io::Result<String>,
+    >,
+) -> anyhow::Result<()> {
     let OptScriptsRm {
         manifest_path,
         color,
         dry_run,
+        delete_gist,
+        token_stdin,
         package,
     } = opt;
 
     let Context {
-        cwd, init_logger, ..
+        cwd,
+        home_dir,
+        read_input,
+        read_password,
+        init_logger,
+        ..
     } = ctx;
 
     init_logger(color);
@@ -710,6 +1098,25 @@ fn rm(opt: OptScriptsRm, ctx: Context<impl Sized, impl Sized, impl Sized>) -> an
         .parent()
         .expect("`manifest_path` should end with \"Cargo.toml\"");
 
+    let mut config = CargoScriptsConfig::load(&metadata.workspace_root)?;
+    let gist = config.gist_ids.remove(&package.name);
+
+    if delete_gist {
+        if let Some(gist) = &gist {
+            let (remote, token) = config.remote_with_token(
+                gist.backend,
+                token_stdin,
+                dry_run,
+                home_dir.as_deref(),
+                read_input,
+                read_password,
+            )?;
+            delete_remote(&*remote, &token, &gist.id, dry_run)?;
+        } else {
+            warn!("{:?} is not in `gist_ids`", package.name);
+        }
+    }
+
     modify_ws(
         &metadata.workspace_root,
         None,
@@ -723,6 +1130,11 @@ fn rm(opt: OptScriptsRm, ctx: Context<impl Sized, impl Sized, impl Sized>) -> an
         remove_dir_all::remove_dir_all(dir)?;
     }
     info!("Removed {}", dir.display());
+
+    if let Some(gist) = gist {
+        info!("`gist_ids.{:?}`: {:?} → None", package.name, gist.id);
+        config.store(dry_run)?;
+    }
     Ok(())
 }
 
@@ -797,6 +1209,7 @@ fn import(
         color,
         dry_run,
         path,
+        split_modules,
         file,
     } = opt;
 
@@ -817,9 +1230,31 @@ fn import(
         .map(read)
         .unwrap_or_else(move || read_input().map_err(Into::into))?;
 
-    import_script(&workspace_root, &content, dry_run, |package_name| {
-        cwd.join(path.unwrap_or_else(|| workspace_root.join(package_name)))
-    })
+    let default_package_name = || {
+        file.as_deref()
+            .and_then(Path::file_stem)
+            .or_else(|| path.as_deref().and_then(Path::file_name))
+            .map(package_name_from_file_name)
+            .with_context(|| {
+                "could not determine the package name for `// cargo-deps:`. specify the file or \
+                 `--path`"
+            })
+    };
+
+    import_script(
+        &workspace_root,
+        &content,
+        &[],
+        split_modules,
+        dry_run,
+        default_package_name,
+        |package_name| {
+            cwd.join(
+                path.clone()
+                    .unwrap_or_else(|| workspace_root.join(package_name)),
+            )
+        },
+    )
     .map(drop)
 }
 
@@ -830,6 +1265,8 @@ fn export(
     let OptScriptsExport {
         manifest_path,
         color,
+        format,
+        bundle,
         package,
     } = opt;
 
@@ -840,32 +1277,85 @@ fn export(
     init_logger(color);
 
     let metadata = cargo_metadata_no_deps_expecting_virtual(manifest_path.as_deref(), color, &cwd)?;
+    let config = CargoScriptsConfig::load(&metadata.workspace_root)?;
     let (src_path, cargo_toml) = metadata.find_package(&package)?.find_default_bin()?;
-    let (code, _) = replace_cargo_lang_code(&read(src_path)?, &cargo_toml, || {
-        anyhow!(
-            "could not find the `cargo` code block: {}",
-            src_path.display(),
-        )
-    })?;
+    let code = if bundle {
+        modules::bundle(src_path)?.0
+    } else {
+        read(src_path)?
+    };
+    let code = embed_manifest(&code, &cargo_toml, format.or(config.export_format))?;
 
     ctx.stdout.write_all(code.as_ref())?;
     ctx.stdout.flush().map_err(Into::into)
 }
 
+fn convert(
+    opt: OptScriptsConvert,
+    ctx: Context<impl Sized, impl Sized, impl Sized>,
+) -> anyhow::Result<()> {
+    let OptScriptsConvert {
+        color,
+        dry_run,
+        to,
+        file,
+    } = opt;
+
+    let Context {
+        cwd, init_logger, ..
+    } = ctx;
+
+    init_logger(color);
+
+    let file = cwd.join(file);
+    let code = read(&file)?;
+    let (from, cargo_toml) = extract_manifest(&code)?.with_context(|| {
+        format!(
+            "could not find the `cargo` code block, frontmatter, or `// cargo-deps:` line: {}",
+            file.display(),
+        )
+    })?;
+
+    if from == to {
+        info!("{} is already in `{}`", file.display(), <&str>::from(to));
+        return Ok(());
+    }
+    info!(
+        "Converting {} from `{}` to `{}`",
+        file.display(),
+        <&str>::from(from),
+        <&str>::from(to),
+    );
+    write(
+        &file,
+        embed_manifest(&code, &cargo_toml, Some(to))?,
+        dry_run,
+    )
+}
+
 fn gist_clone(
     opt: OptScriptsGistClone,
-    ctx: Context<impl Sized, impl Sized, impl Sized>,
+    ctx: Context<impl Sized, impl FnOnce() -> io::Result<String>, impl Sized>,
 ) -> anyhow::Result<()> {
     let OptScriptsGistClone {
         manifest_path,
         color,
         dry_run,
         path,
+        split_modules,
+        no_auth,
+        token_stdin,
+        offline,
+        backend,
         gist_id,
     } = opt;
 
     let Context {
-        cwd, init_logger, ..
+        cwd,
+        home_dir,
+        read_input,
+        init_logger,
+        ..
     } = ctx;
 
     init_logger(color);
@@ -874,74 +1364,359 @@ fn gist_clone(
         cargo_metadata_no_deps_expecting_virtual(manifest_path.as_deref(), color, &cwd)?;
 
     let mut config = CargoScriptsConfig::load(&workspace_root)?;
+    let (remote, token) = config.remote_with_optional_token(
+        backend,
+        token_stdin,
+        home_dir.as_deref(),
+        read_input,
+        no_auth,
+        offline,
+    )?;
 
-    let (script, _) = retrieve_rust_code(&gist_id)?;
-    let package_name = import_script(&workspace_root, &script, dry_run, |package_name| {
-        cwd.join(path.unwrap_or_else(|| workspace_root.join(package_name)))
-    })?;
-    let old_gist_id = config.gist_ids.get(&package_name).cloned();
+    let (gist_id, rev) = match gist_id.splitn(2, '@').collect::<Vec<_>>()[..] {
+        [gist_id, rev] => (gist_id.to_owned(), Some(rev)),
+        _ => (gist_id.clone(), None),
+    };
+
+    let RemoteScript {
+        script,
+        files,
+        revision,
+        ..
+    } = RemoteScript::fetch(&*remote, token.as_deref(), &gist_id, rev)?;
+    // The sync base is the latest revision even with `@<rev>`, so that a later push is not
+    // mistaken for being behind.
+    let revision = match rev {
+        Some(_) => remote.fetch(token.as_deref(), &gist_id, None)?.revision,
+        None => revision,
+    };
+    let default_package_name = || {
+        Ok(package_name_from_file_name(
+            path.as_deref()
+                .and_then(Path::file_name)
+                .or_else(|| Path::new(&script.filename).file_stem())
+                .unwrap_or_else(|| gist_id.as_ref()),
+        ))
+    };
+    let package_name = import_script(
+        &workspace_root,
+        &script.content,
+        &files,
+        split_modules,
+        dry_run,
+        default_package_name,
+        |package_name| {
+            cwd.join(
+                path.clone()
+                    .unwrap_or_else(|| workspace_root.join(package_name)),
+            )
+        },
+    )?;
+    let old_gist_id = config.gist_ids.get(&package_name).map(|g| g.id.clone());
     info!(
         "`gist_ids.{:?}`: {:?} -> {:?}",
         package_name, old_gist_id, gist_id,
     );
-    config.gist_ids.insert(package_name, gist_id);
+    if let Some(html_url) = remote.html_url(&gist_id)? {
+        info!("{}", html_url);
+    }
+    config.gist_ids.insert(
+        package_name.clone(),
+        CargoScriptsConfigGist {
+            backend,
+            id: gist_id,
+            revision,
+            filename: Some(script.filename),
+            package: Some(package_name),
+            split_modules,
+        },
+    );
     config.store(dry_run)?;
     Ok(())
 }
 
 fn gist_pull(
     opt: OptScriptsGistPull,
-    ctx: Context<impl Sized, impl Sized, impl Sized>,
+    ctx: Context<impl Write, impl FnOnce() -> io::Result<String>, impl Sized>,
 ) -> anyhow::Result<()> {
     let OptScriptsGistPull {
         manifest_path,
         color,
         dry_run,
-        package,
+        theirs,
+        ours,
+        no_auth,
+        token_stdin,
+        rev,
+        all,
+        jobs,
+        packages,
     } = opt;
 
     let Context {
-        cwd, init_logger, ..
+        cwd,
+        home_dir,
+        stdout,
+        read_input,
+        init_logger,
+        ..
     } = ctx;
 
     init_logger(color);
 
     let metadata = cargo_metadata_no_deps_expecting_virtual(manifest_path.as_deref(), color, &cwd)?;
-    let package = metadata.find_package(&package)?;
+    let mut config = CargoScriptsConfig::load(&metadata.workspace_root)?;
+    let packages = if all {
+        config.gist_ids.keys().cloned().collect()
+    } else {
+        packages
+    };
+    if rev.is_some() && packages.len() > 1 {
+        bail!("`--rev` cannot be used with multiple packages");
+    }
+    let remotes = config.remotes(
+        packages
+            .iter()
+            .flat_map(|p| config.gist_ids.get(p))
+            .map(|g| g.backend),
+        token_stdin,
+        home_dir.as_deref(),
+        read_input,
+        no_auth,
+        false,
+    )?;
 
-    let CargoScriptsConfig { gist_ids, .. } = CargoScriptsConfig::load(&metadata.workspace_root)?;
-    let gist_id = gist_ids
-        .get(&package.name)
-        .ok_or_else(|| anyhow!("could not find the `gist_id` for {:?}", package.name))?;
-    let (pulled_code, _) = retrieve_rust_code(gist_id)?;
-    let (pulled_code, pulled_cargo_toml) = replace_cargo_lang_code_with_default(&pulled_code)?;
-    let (src_path, prev_cargo_toml) = package.find_default_bin()?;
-
-    for (path, orig, edit) in &[
-        (src_path, read(src_path)?, pulled_code),
-        (&package.manifest_path, prev_cargo_toml, pulled_cargo_toml),
-    ] {
-        if orig == edit {
-            info!("No changes: {}", path.display());
-        } else {
-            info!("`{}`:", path.display());
-            for diff in diff::lines(orig, edit) {
-                let (pref, line) = match diff {
-                    diff::Result::Left(l) => ("-", l),
-                    diff::Result::Both(l, _) => (" ", l),
-                    diff::Result::Right(l) => ("+", l),
-                };
-                info!("│{}{}", pref, line);
+    return sync_packages(
+        stdout,
+        &mut config,
+        &packages,
+        all || packages.len() > 1,
+        jobs,
+        dry_run,
+        |package_name, gist| {
+            pull(
+                &metadata,
+                &remotes,
+                package_name,
+                gist,
+                rev.as_deref(),
+                dry_run,
+                theirs,
+                ours,
+            )
+        },
+    );
+
+    #[allow(clippy::too_many_arguments)]
+    fn pull(
+        metadata: &cargo_metadata::Metadata,
+        remotes: &Remotes<Option<String>>,
+        package_name: &str,
+        gist: Option<&CargoScriptsConfigGist>,
+        rev: Option<&str>,
+        dry_run: bool,
+        theirs: bool,
+        ours: bool,
+    ) -> anyhow::Result<Synced> {
+        let package = metadata.find_package(package_name)?;
+        let gist =
+            gist.ok_or_else(|| anyhow!("could not find the `gist_id` for {:?}", package.name))?;
+        let (api, token) = &remotes[&gist.backend];
+        let token = token.as_deref();
+        let (src_path, _) = package.find_default_bin()?;
+        let package_dir = package
+            .manifest_path
+            .parent()
+            .expect("`manifest_path` should end with \"Cargo.toml\"");
+
+        let remote = RemoteScript::fetch(&**api, token, &gist.id, rev)?;
+        // `--rev` only chooses the content. The sync base is the latest revision.
+        let revision = match rev {
+            Some(_) => api.fetch(token, &gist.id, None)?.revision,
+            None => remote.revision.clone(),
+        };
+        let filename = remote.script.filename.clone();
+        let unpack = |remote: RemoteScript| {
+            remote.unpack(
+                &metadata.workspace_root,
+                &package.name,
+                src_path,
+                &package.manifest_path,
+                package_dir,
+                gist.split_modules,
+            )
+        };
+        let remote = unpack(remote)?;
+        let base = match gist.revision.as_deref() {
+            Some(rev) => match RemoteScript::fetch(&**api, token, &gist.id, Some(rev)) {
+                Ok(base) => Some(unpack(base)?),
+                Err(err) if is_not_found(&err) => {
+                    warn!("Could not retrieve the last synced revision: {:#}", err);
+                    None
+                }
+                Err(err) => return Err(err),
+            },
+            None => None,
+        };
+
+        let mut changes = vec![];
+        let mut conflicts = vec![];
+        for (path, remote) in remote {
+            let local = if path.exists() {
+                read(&path)?
+            } else {
+                "".to_owned()
+            };
+            let base = base.as_ref().and_then(|base| {
+                base.iter()
+                    .find(|(p, _)| *p == path)
+                    .map(|(_, c)| &**c)
+                    .or(Some(""))
+            });
+            let resolve = if theirs {
+                Some(MergeSide::Theirs)
+            } else if ours {
+                Some(MergeSide::Ours)
+            } else {
+                None
+            };
+            match merge(base, &local, &remote, resolve) {
+                Some(merged) => changes.push((path, local, merged)),
+                None => conflicts.push(path),
             }
-            write(&path, edit, dry_run)?;
         }
+
+        if !conflicts.is_empty() {
+            bail!(
+                "{}: [{}] (use `--theirs` or `--ours` to resolve)",
+                if base.is_some() {
+                    "conflicts found"
+                } else {
+                    "no merge base is available and the following files differ"
+                },
+                conflicts.iter().format_with(", ", |p, f| f(&p.display())),
+            );
+        }
+
+        let mut outcome = "up-to-date";
+        for (path, orig, edit) in changes {
+            if orig == edit {
+                info!("No changes: {}", path.display());
+            } else {
+                info!("`{}`:", path.display());
+                for diff in diff::lines(&orig, &edit) {
+                    let (pref, line) = match diff {
+                        diff::Result::Left(l) => ("-", l),
+                        diff::Result::Both(l, _) => (" ", l),
+                        diff::Result::Right(l) => ("+", l),
+                    };
+                    info!("│{}{}", pref, line);
+                }
+                if let Some(parent) = path.parent() {
+                    if !parent.exists() {
+                        create_dir_all(parent, dry_run)?;
+                    }
+                }
+                write(&path, edit, dry_run)?;
+                outcome = "updated";
+            }
+        }
+
+        Ok(Synced {
+            outcome,
+            gist: Some(CargoScriptsConfigGist {
+                backend: gist.backend,
+                id: gist.id.clone(),
+                revision,
+                filename: Some(filename),
+                package: gist.package.clone(),
+                split_modules: gist.split_modules,
+            }),
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum MergeSide {
+    Ours,
+    Theirs,
+}
+
+/// Merges `theirs` into `ours`.
+///
+/// The hunks where `ours` and `theirs` overlap are taken from the side of `resolve`. If no merge
+/// base is available, the whole file is. Returns `None` if `resolve` is `None` in those cases.
+fn merge(
+    base: Option<&str>,
+    ours: &str,
+    theirs: &str,
+    resolve: Option<MergeSide>,
+) -> Option<String> {
+    // Conflict markers longer than any run of `<`, `=`, or `>` in the inputs cannot be mistaken
+    // for the content.
+    let marker_len = [base.unwrap_or(""), ours, theirs]
+        .iter()
+        .flat_map(|s| s.split(|c| !matches!(c, '<' | '=' | '>')))
+        .map(str::len)
+        .max()
+        .unwrap_or(0)
+        .max(6)
+        + 1;
+
+    let merged = match base {
+        _ if ours == theirs => Ok(theirs.to_owned()),
+        Some(base) if base == ours => Ok(theirs.to_owned()),
+        Some(base) if base == theirs => Ok(ours.to_owned()),
+        Some(base) => diffy::MergeOptions::new()
+            .set_conflict_marker_length(marker_len)
+            .set_conflict_style(diffy::ConflictStyle::Merge)
+            .merge(base, ours, theirs)
+            .map_err(Some),
+        None => Err(None),
+    };
+    match (merged, resolve) {
+        (Ok(merged), _) => Some(merged),
+        (Err(Some(conflicted)), Some(side)) => {
+            Some(resolve_conflicts(&conflicted, marker_len, side))
+        }
+        (Err(None), Some(MergeSide::Ours)) => Some(ours.to_owned()),
+        (Err(None), Some(MergeSide::Theirs)) => Some(theirs.to_owned()),
+        (Err(_), None) => None,
     }
-    Ok(())
+}
+
+/// Replaces each conflict in the output of `diffy` with the lines of `side`.
+///
+/// A marker follows the last line of a side directly if the line has no line terminator.
+fn resolve_conflicts(conflicted: &str, marker_len: usize, side: MergeSide) -> String {
+    let open = format!("{} ours\n", "<".repeat(marker_len));
+    let separator = format!("{}\n", "=".repeat(marker_len));
+    let close = format!("{} theirs\n", ">".repeat(marker_len));
+
+    let mut resolved = "".to_owned();
+    let mut rest = conflicted;
+    while let Some(start) = rest.find(&open) {
+        resolved += &rest[..start];
+        rest = &rest[start + open.len()..];
+        let (ours, after_ours) = rest.split_at(rest.find(&separator).unwrap());
+        rest = &after_ours[separator.len()..];
+        let (theirs, after_theirs) = rest.split_at(rest.find(&close).unwrap());
+        rest = &after_theirs[close.len()..];
+        resolved += match side {
+            MergeSide::Ours => ours,
+            MergeSide::Theirs => theirs,
+        };
+    }
+    resolved + rest
 }
 
 fn gist_push(
     opt: OptScriptsGistPush,
-    ctx: Context<impl Sized, impl Sized, impl FnMut(&str) -> io::Result<String>>,
+    ctx: Context<
+        impl Write,
+        impl FnOnce() -> io::Result<String>,
+        impl FnMut(&str) -> io::Result<String>,
+    >,
 ) -> anyhow::Result<()> {
     let OptScriptsGistPush {
         manifest_path,
@@ -949,13 +1724,20 @@ fn gist_push(
         dry_run,
         set_upstream,
         private,
+        backend,
         description,
-        package,
+        force,
+        token_stdin,
+        all,
+        jobs,
+        packages,
     } = opt;
 
     let Context {
         cwd,
         home_dir,
+        stdout,
+        read_input,
         read_password,
         init_logger,
         ..
@@ -965,131 +1747,700 @@ fn gist_push(
 
     let metadata = cargo_metadata_no_deps_expecting_virtual(manifest_path.as_deref(), color, &cwd)?;
     let mut config = CargoScriptsConfig::load(&metadata.workspace_root)?;
-    let github_token = CargoScriptsConfig::load(&metadata.workspace_root)?
-        .github_token
-        .load_or_ask(dry_run, home_dir.as_deref(), read_password)?;
-
-    let (src_path, cargo_toml) = metadata.find_package(&package)?.find_default_bin()?;
-    let (local, _) = replace_cargo_lang_code(&read(src_path)?, &cargo_toml, || {
-        anyhow!(
-            "could not find the `cargo` code block: {}",
-            src_path.display(),
-        )
-    })?;
-
-    let state = if let Some(gist_id) = config.gist_ids.get(&package) {
-        let (remote_code, remote_description) = retrieve_rust_code(gist_id)?;
-        if remote_code == local {
-            State::UpToDate
-        } else {
-            State::Forward(gist_id, remote_description)
-        }
+    let packages = if all {
+        config.gist_ids.keys().cloned().collect()
     } else {
-        State::NotExist
+        packages
     };
+    let remotes = config.remotes_with_tokens(
+        packages
+            .iter()
+            .flat_map(|package| match config.gist_ids.get(package) {
+                Some(gist) => Some(gist.backend),
+                None if set_upstream => Some(backend),
+                None => None,
+            }),
+        token_stdin,
+        dry_run,
+        home_dir.as_deref(),
+        read_input,
+        read_password,
+    )?;
 
-    return match state {
-        State::UpToDate => {
-            info!("Up to date");
-            Ok(())
-        }
-        State::Forward(gist_id, remote_description) => {
-            let url = "https://api.github.com/gists/"
-                .parse::<Url>()
-                .unwrap()
-                .join(gist_id)?;
+    let export_format = config.export_format;
+
+    return sync_packages(
+        stdout,
+        &mut config,
+        &packages,
+        all || packages.len() > 1,
+        jobs,
+        dry_run,
+        |package_name, gist| {
+            push(
+                &metadata,
+                &remotes,
+                package_name,
+                gist,
+                export_format,
+                dry_run,
+                set_upstream,
+                private,
+                backend,
+                description.as_deref(),
+                force,
+            )
+        },
+    );
 
-            if dry_run {
-                info!("[dry-run] PATCH {}", url);
+    #[allow(clippy::too_many_arguments)]
+    fn push(
+        metadata: &cargo_metadata::Metadata,
+        remotes: &Remotes<String>,
+        package_name: &str,
+        gist: Option<&CargoScriptsConfigGist>,
+        export_format: Option<ManifestFormat>,
+        dry_run: bool,
+        set_upstream: bool,
+        private: bool,
+        backend: Backend,
+        description: Option<&str>,
+        force: bool,
+    ) -> anyhow::Result<Synced> {
+        let package = metadata.find_package(package_name)?;
+        let split_modules = gist.is_some_and(|gist| gist.split_modules);
+        let (local, local_files) = package.export_script(export_format, split_modules)?;
+
+        if gist.is_none() && !set_upstream {
+            bail!("to create a new gist, enable `--set-upstream`");
+        }
+        let backend = gist.map_or(backend, |gist| gist.backend);
+        let (api, token) = &remotes[&backend];
+
+        let state = if let Some(
+            gist @ CargoScriptsConfigGist {
+                id: gist_id,
+                revision: last_synced,
+                ..
+            },
+        ) = gist
+        {
+            let remote = RemoteScript::fetch(&**api, Some(token), gist_id, None)?;
+            let (remote_filename, script_filename) =
+                gist.script_filenames(&remote.script.filename, &package.name);
+            if remote.has_same_content(&local, &local_files) && script_filename == remote_filename {
+                State::UpToDate(remote.revision, remote_filename)
             } else {
-                let payload = json!({
-                    "description": description.unwrap_or(remote_description),
-                    "files": {
-                        format!("{}.rs", package): {
-                          "content": local
+                match (last_synced, &remote.revision) {
+                    (Some(last_synced), Some(revision)) if last_synced != revision => {
+                        if force {
+                            warn!(
+                                "The gist has been modified since the last sync ({} → {}). \
+                                 Overwriting it since `--force` is enabled",
+                                last_synced, revision,
+                            );
+                        } else {
+                            bail!(
+                                "the remote has new changes since the last sync ({} → {}). \
+                                 pull them first with `cargo scripts gist pull {}`, or enable \
+                                 `--force`",
+                                last_synced,
+                                revision,
+                                package.name,
+                            );
                         }
                     }
+                    (None, Some(_)) => warn!(
+                        "No revision of `{}` is recorded. Could not check if the gist has been \
+                         modified",
+                        gist_id,
+                    ),
+                    _ => {}
+                }
+                let removed = remote.removed_files(&local_files)?;
+                State::Forward(gist_id, remote_filename, script_filename, removed)
+            }
+        } else {
+            State::NotExist(format!("{}.rs", package.name))
+        };
+
+        let files = |script_filename: &str| {
+            iter::once(Ok((script_filename.to_owned(), local.clone())))
+                .chain(
+                    local_files
+                        .iter()
+                        .map(|(path, content)| Ok((remote_filename(path)?, content.clone()))),
+                )
+                .collect::<anyhow::Result<IndexMap<_, _>>>()
+        };
+
+        let (outcome, pushed, script_filename) = match state {
+            State::UpToDate(revision, filename) => {
+                info!("Up to date");
+                return Ok(Synced {
+                    outcome: "up-to-date",
+                    gist: gist.map(|gist| CargoScriptsConfigGist {
+                        backend,
+                        id: gist.id.clone(),
+                        revision,
+                        filename: Some(filename),
+                        package: Some(package.name.clone()),
+                        split_modules: gist.split_modules,
+                    }),
                 });
+            }
+            State::Forward(gist_id, remote_filename, script_filename, removed) => {
+                for filename in &removed {
+                    info!("Removing {}", filename);
+                }
+                let rename = if remote_filename != script_filename {
+                    info!("Renaming {} to {}", remote_filename, script_filename);
+                    Some((&*remote_filename, &*script_filename))
+                } else {
+                    None
+                };
+                let pushed = api.update(
+                    token,
+                    gist_id,
+                    &files(&script_filename)?,
+                    &removed,
+                    rename,
+                    description,
+                    dry_run,
+                )?;
+                if let Some(Pushed { id, .. }) = &pushed {
+                    info!("Updated `{}`", id);
+                }
+                ("updated", pushed, script_filename)
+            }
+            State::NotExist(script_filename) => {
+                let pushed = api.create(
+                    token,
+                    &files(&script_filename)?,
+                    description.unwrap_or_default(),
+                    !private,
+                    dry_run,
+                )?;
+                if let Some(Pushed { id, .. }) = &pushed {
+                    info!("Created `{}`", id);
+                }
+                ("created", pushed, script_filename)
+            }
+        };
 
-                info!("PATCH {}", url);
-                let res = ureq::patch(url.as_ref())
-                    .set("Authorization", &format!("token {}", github_token))
-                    .set("User-Agent", USER_AGENT)
-                    .send_json(payload);
-                raise_synthetic_error(&res)?;
-                info!("{} {}", res.status(), res.status_text());
-                ensure!(res.status() == 200, "expected 200");
-                serde_json::from_str::<serde_json::Value>(&res.into_string()?)?;
-
-                info!("Updated `{}`", gist_id);
+        let gist = match pushed {
+            Some(Pushed { id, revision }) => {
+                if let Some(html_url) = api.html_url(&id)? {
+                    info!("{}", html_url);
+                }
+                Some(CargoScriptsConfigGist {
+                    backend,
+                    id,
+                    revision,
+                    filename: Some(script_filename),
+                    package: Some(package.name.clone()),
+                    split_modules,
+                })
             }
-            Ok(())
-        }
-        State::NotExist => {
-            static URL: &str = "https://api.github.com/gists";
+            None => None,
+        };
+        Ok(Synced { outcome, gist })
+    }
 
-            if !set_upstream {
-                bail!("to create a new gist, enable `--set-upstream`");
-            } else if dry_run {
-                info!("[dry-run] POST {}", URL);
-                Ok(())
-            } else {
-                let payload = json!({
-                    "files": {
-                        format!("{}.rs", package): {
-                          "content": local
-                        }
-                    },
-                    "description": description.unwrap_or_default(),
-                    "public": !private
-                });
+    enum State<'a> {
+        UpToDate(Option<String>, String),
+        Forward(&'a str, String, String, Vec<String>),
+        NotExist(String),
+    }
+}
 
-                info!("POST {}", URL);
-                let res = ureq::post(URL)
-                    .set("Authorization", &format!("token {}", github_token))
-                    .set("User-Agent", USER_AGENT)
-                    .send_json(payload);
-                raise_synthetic_error(&res)?;
-                ensure!(res.status() == 201, "expected 201");
-                let CreateGist { id } = serde_json::from_str(&res.into_string()?)?;
+/// Runs `sync` for each package with at most `jobs` threads, then updates `gist_ids`.
+///
+/// When `batch` is `true`, a failure of one package does not stop the others and a summary is
+/// printed at the end. With more than one thread, the logs of each package are held back and
+/// printed in order of `packages` so that they do not interleave.
+fn sync_packages(
+    mut stdout: impl Write,
+    config: &mut CargoScriptsConfig,
+    packages: &[String],
+    batch: bool,
+    jobs: NonZeroUsize,
+    dry_run: bool,
+    sync: impl Fn(&str, Option<&CargoScriptsConfigGist>) -> anyhow::Result<Synced> + Sync,
+) -> anyhow::Result<()> {
+    let results = {
+        let gist_ids = &config.gist_ids;
+        let next = AtomicUsize::new(0);
+        let results = Mutex::new(packages.iter().map(|_| None).collect::<Vec<_>>());
+        let num_threads = jobs.get().min(packages.len());
+        thread::scope(|scope| {
+            for _ in 0..num_threads {
+                scope.spawn(|| loop {
+                    let i = next.fetch_add(1, atomic::Ordering::SeqCst);
+                    if i >= packages.len() {
+                        break;
+                    }
+                    let sync = || sync(&packages[i], gist_ids.get(&packages[i]));
+                    let result = if num_threads > 1 {
+                        capture_logs(sync)
+                    } else {
+                        (sync(), vec![])
+                    };
+                    results.lock().unwrap()[i] = Some(result);
+                });
+            }
+        });
+        results
+            .into_inner()
+            .unwrap()
+            .into_iter()
+            .map(|r| r.expect("every package should be processed"))
+            .map(|(result, logs)| {
+                for (level, msg) in logs {
+                    log::log!(level, "{}", msg);
+                }
+                result
+            })
+            .collect::<Vec<_>>()
+    };
 
-                info!("Created `{}`", id);
+    let mut modified = false;
+    for (package, result) in packages.iter().zip(&results) {
+        if let Ok(Synced {
+            gist: Some(gist), ..
+        }) = result
+        {
+            let prev = config.gist_ids.get(package);
+            if prev.map(|g| &g.id) != Some(&gist.id) {
                 info!(
-                    "`gist_ids.{:?}`: {:?} →> {:?}",
+                    "`gist_ids.{:?}`: {:?} → {:?}",
                     package,
-                    config.gist_ids.get(&package),
-                    id,
+                    prev.map(|g| &g.id),
+                    gist.id,
+                );
+            }
+            if prev.and_then(|g| g.revision.as_ref()) != gist.revision.as_ref() {
+                info!(
+                    "`gist_ids.{:?}.revision`: {:?} → {:?}",
+                    package,
+                    prev.and_then(|g| g.revision.as_ref()),
+                    gist.revision,
                 );
-                config.gist_ids.insert(package, id);
-                config.store(false)
+            }
+            if prev.and_then(|g| g.filename.as_ref()) != gist.filename.as_ref() {
+                info!(
+                    "`gist_ids.{:?}.filename`: {:?} → {:?}",
+                    package,
+                    prev.and_then(|g| g.filename.as_ref()),
+                    gist.filename,
+                );
+            }
+            if prev.and_then(|g| g.package.as_ref()) != gist.package.as_ref() {
+                info!(
+                    "`gist_ids.{:?}.package`: {:?} → {:?}",
+                    package,
+                    prev.and_then(|g| g.package.as_ref()),
+                    gist.package,
+                );
+            }
+            if prev != Some(gist) {
+                config.gist_ids.insert(package.clone(), gist.clone());
+                modified = true;
             }
         }
-    };
+    }
+    if modified {
+        config.store(dry_run)?;
+    }
 
-    enum State<'a> {
-        UpToDate,
-        Forward(&'a str, String),
-        NotExist,
+    if !batch {
+        return results.into_iter().try_for_each(|r| r.map(drop));
     }
 
-    #[derive(Deserialize, Debug)]
-    struct CreateGist {
-        id: String,
+    let width = packages.iter().map(|p| p.len()).max().unwrap_or(0);
+    let mut num_failures = 0;
+    for (package, result) in packages.iter().zip(&results) {
+        match result {
+            Ok(Synced { outcome, .. }) => {
+                writeln!(stdout, "{:<width$}  {}", package, outcome, width = width)?
+            }
+            Err(err) => {
+                num_failures += 1;
+                writeln!(
+                    stdout,
+                    "{:<width$}  failed: {:#}",
+                    package,
+                    err,
+                    width = width
+                )?;
+            }
+        }
+    }
+    stdout.flush()?;
+
+    ensure!(
+        num_failures == 0,
+        "{} of {} packages failed",
+        num_failures,
+        packages.len(),
+    );
+    Ok(())
+}
+
+#[derive(Debug)]
+struct Synced {
+    outcome: &'static str,
+    gist: Option<CargoScriptsConfigGist>,
+}
+
+fn gist_status(
+    opt: OptScriptsGistStatus,
+    mut ctx: Context<impl Write, impl FnOnce() -> io::Result<String>, impl Sized>,
+) -> anyhow::Result<()> {
+    let OptScriptsGistStatus {
+        manifest_path,
+        color,
+        json,
+        offline,
+        token_stdin,
+    } = opt;
+
+    let Context {
+        cwd,
+        home_dir,
+        read_input,
+        init_logger,
+        ..
+    } = ctx;
+
+    init_logger(color);
+
+    let metadata = cargo_metadata_no_deps_expecting_virtual(manifest_path.as_deref(), color, &cwd)?;
+    let config = CargoScriptsConfig::load(&metadata.workspace_root)?;
+    let remotes = config.remotes(
+        config.gist_ids.values().map(|g| g.backend),
+        token_stdin,
+        home_dir.as_deref(),
+        read_input,
+        false,
+        offline,
+    )?;
+
+    let rows = config
+        .gist_ids
+        .iter()
+        .map(|(package_name, gist)| {
+            let (api, token) = &remotes[&gist.backend];
+            let (status, error) = match sync_status(
+                &metadata,
+                &**api,
+                token.as_deref(),
+                package_name,
+                gist,
+                config.export_format,
+            ) {
+                Ok(status) => (status, None),
+                Err(err) => (SyncStatus::Error, Some(format!("{:#}", err))),
+            };
+            Row {
+                package: package_name,
+                gist_id: &gist.id,
+                status,
+                error,
+            }
+        })
+        .collect::<Vec<_>>();
+
+    if json {
+        serde_json::to_writer_pretty(&mut ctx.stdout, &rows)?;
+        writeln!(ctx.stdout)?;
+    } else {
+        let package_width = rows.iter().map(|r| r.package.len()).max().unwrap_or(0);
+        let gist_id_width = rows.iter().map(|r| r.gist_id.len()).max().unwrap_or(0);
+        for Row {
+            package,
+            gist_id,
+            status,
+            error,
+        } in rows
+        {
+            write!(
+                ctx.stdout,
+                "{:<package_width$}  {:<gist_id_width$}  {}",
+                package,
+                gist_id,
+                <&str>::from(status),
+                package_width = package_width,
+                gist_id_width = gist_id_width,
+            )?;
+            if let Some(error) = error {
+                write!(ctx.stdout, " ({})", error)?;
+            }
+            writeln!(ctx.stdout)?;
+        }
+    }
+    return ctx.stdout.flush().map_err(Into::into);
+
+    #[derive(Serialize)]
+    struct Row<'a> {
+        package: &'a str,
+        gist_id: &'a str,
+        status: SyncStatus,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        error: Option<String>,
+    }
+}
+
+fn gist_list(
+    opt: OptScriptsGistList,
+    mut ctx: Context<
+        impl Write,
+        impl FnOnce() -> io::Result<String>,
+        impl FnMut(&str) -> io::Result<String>,
+    >,
+) -> anyhow::Result<()> {
+    let OptScriptsGistList {
+        manifest_path,
+        color,
+        backend,
+        token_stdin,
+        no_check_manifests,
+    } = opt;
+
+    let Context {
+        cwd,
+        home_dir,
+        read_input,
+        read_password,
+        init_logger,
+        ..
+    } = ctx;
+
+    init_logger(color);
+
+    let metadata = cargo_metadata_no_deps_expecting_virtual(manifest_path.as_deref(), color, &cwd)?;
+    let config = CargoScriptsConfig::load(&metadata.workspace_root)?;
+    let (remote, token) = config.remote_with_token(
+        backend,
+        token_stdin,
+        false,
+        home_dir.as_deref(),
+        read_input,
+        read_password,
+    )?;
+
+    let mut rows = vec![];
+    for remote::Listing {
+        id,
+        description,
+        public,
+        files,
+    } in remote.list(&token)?
+    {
+        let rust_files = files
+            .into_iter()
+            .filter(|(filename, _)| is_rust(filename))
+            .collect::<Vec<_>>();
+        let package = if let Some((package, _)) = config
+            .gist_ids
+            .iter()
+            .find(|(_, g)| g.backend == backend && g.id == id)
+        {
+            package.clone()
+        } else {
+            let has_manifest = if rust_files.iter().all(|(_, content)| content.is_some()) {
+                Some(has_manifest(rust_files.iter().flat_map(
+                    |(filename, content)| Some((&**filename, content.as_deref()?)),
+                )))
+            } else if !no_check_manifests {
+                match remote.fetch(Some(&token), &id, None) {
+                    Ok(snapshot) => Some(has_manifest(
+                        snapshot.files.iter().map(|(f, c)| (&**f, &**c)),
+                    )),
+                    Err(err) => {
+                        warn!("Could not fetch `{}`: {:#}", id, err);
+                        None
+                    }
+                }
+            } else {
+                None
+            };
+            match has_manifest {
+                Some(true) => "(not cloned)",
+                Some(false) => "-",
+                None => "?",
+            }
+            .to_owned()
+        };
+        let rust_files = rust_files.into_iter().map(|(filename, _)| filename);
+
+        rows.push([
+            id,
+            if public { "public" } else { "secret" }.to_owned(),
+            package,
+            rust_files.format(",").to_string(),
+            description.unwrap_or_default(),
+        ]);
     }
 
-    #[derive(Deserialize, Debug)]
-    struct Gist {
-        files: IndexMap<String, GistFile>,
+    let widths = (0..4)
+        .map(|i| rows.iter().map(|row| row[i].len()).max().unwrap_or(0))
+        .collect::<Vec<_>>();
+    for row in rows {
+        let line = row[..4]
+            .iter()
+            .zip(&widths)
+            .map(|(cell, width)| format!("{:<width$}  ", cell, width = width))
+            .chain(iter::once(row[4].clone()))
+            .join("");
+        writeln!(ctx.stdout, "{}", line.trim_end())?;
     }
+    return ctx.stdout.flush().map_err(Into::into);
 
-    #[derive(Deserialize, Debug)]
-    struct GistFile {
-        filename: String,
-        truncated: bool,
-        content: String,
+    fn has_manifest<'a>(files: impl IntoIterator<Item = (&'a str, &'a str)>) -> bool {
+        files.into_iter().any(|(filename, content)| {
+            is_rust(filename) && ManifestFormat::detect(content).is_some()
+        })
     }
 }
 
+fn gist_log(
+    opt: OptScriptsGistLog,
+    mut ctx: Context<impl Write, impl FnOnce() -> io::Result<String>, impl Sized>,
+) -> anyhow::Result<()> {
+    let OptScriptsGistLog {
+        manifest_path,
+        color,
+        token_stdin,
+        package,
+    } = opt;
+
+    let Context {
+        cwd,
+        home_dir,
+        read_input,
+        init_logger,
+        ..
+    } = ctx;
+
+    init_logger(color);
+
+    let metadata = cargo_metadata_no_deps_expecting_virtual(manifest_path.as_deref(), color, &cwd)?;
+    let config = CargoScriptsConfig::load(&metadata.workspace_root)?;
+    let gist = config
+        .gist_ids
+        .get(&package)
+        .ok_or_else(|| anyhow!("could not find the `gist_id` for {:?}", package))?;
+    let (remote, token) = config.remote_with_optional_token(
+        gist.backend,
+        token_stdin,
+        home_dir.as_deref(),
+        read_input,
+        false,
+        false,
+    )?;
+
+    let remote::Snapshot { history, .. } = remote.fetch(token.as_deref(), &gist.id, None)?;
+
+    if history.is_empty() {
+        warn!("{} does not keep revisions of `{}`", remote.name(), gist.id);
+    }
+
+    for remote::Revision {
+        version,
+        committed_at,
+        additions,
+        deletions,
+    } in history
+    {
+        writeln!(
+            ctx.stdout,
+            "{}  {}  +{} -{}{}",
+            version,
+            committed_at,
+            additions,
+            deletions,
+            if gist.revision.as_ref() == Some(&version) {
+                "  (synced)"
+            } else {
+                ""
+            },
+        )?;
+    }
+    ctx.stdout.flush().map_err(Into::into)
+}
+
+fn gist_delete(
+    opt: OptScriptsGistDelete,
+    ctx: Context<
+        impl Sized,
+        impl FnOnce() -> io::Result<String>,
+        impl FnMut(&str) -> io::Result<String>,
+    >,
+) -> anyhow::Result<()> {
+    let OptScriptsGistDelete {
+        manifest_path,
+        color,
+        dry_run,
+        token_stdin,
+        package,
+    } = opt;
+
+    let Context {
+        cwd,
+        home_dir,
+        read_input,
+        read_password,
+        init_logger,
+        ..
+    } = ctx;
+
+    init_logger(color);
+
+    let metadata = cargo_metadata_no_deps_expecting_virtual(manifest_path.as_deref(), color, &cwd)?;
+    let mut config = CargoScriptsConfig::load(&metadata.workspace_root)?;
+    let gist = config
+        .gist_ids
+        .remove(&package)
+        .ok_or_else(|| anyhow!("could not find the `gist_id` for {:?}", package))?;
+    let (remote, token) = config.remote_with_token(
+        gist.backend,
+        token_stdin,
+        dry_run,
+        home_dir.as_deref(),
+        read_input,
+        read_password,
+    )?;
+
+    delete_remote(&*remote, &token, &gist.id, dry_run)?;
+
+    info!("`gist_ids.{:?}`: {:?} → None", package, gist.id);
+    config.store(dry_run)
+}
+
+/// Deletes `id` from `remote`. A gist that is already gone is not an error.
+///
+/// A 404 is also returned for a gist that the token cannot delete, so it is trusted only if the
+/// gist cannot be fetched with the token either.
+fn delete_remote(remote: &dyn Remote, token: &str, id: &str, dry_run: bool) -> anyhow::Result<()> {
+    match remote.delete(token, id, dry_run) {
+        Ok(()) if dry_run => {}
+        Ok(()) => info!("Deleted `{}`", id),
+        Err(err) if is_not_found(&err) => match remote.fetch(Some(token), id, None) {
+            Ok(_) => bail!(
+                "`{}` exists but could not be deleted (is it owned by another account?)",
+                id,
+            ),
+            Err(err) if is_not_found(&err) => warn!("`{}` does not exist", id),
+            Err(err) => {
+                return Err(err.context(format!("could not check whether `{}` still exists", id)))
+            }
+        },
+        Err(err) => return Err(err),
+    }
+    Ok(())
+}
+
 fn config_set_base(
     opt: OptScriptsConfigSetBase,
     ctx: Context<impl Sized, impl Sized, impl Sized>,
@@ -1124,6 +2475,7 @@ fn config_set_gist_id(
         manifest_path,
         color,
         dry_run,
+        backend,
         package,
         gist_id,
     } = opt;
@@ -1140,10 +2492,85 @@ fn config_set_gist_id(
     info!(
         "`gist_ids.{:?}`: {:?} → {:?}",
         package,
-        config.gist_ids.get(&package),
+        config.gist_ids.get(&package).map(|g| &g.id),
         gist_id,
     );
-    config.gist_ids.insert(package, gist_id);
+    config.gist_ids.insert(
+        package,
+        CargoScriptsConfigGist {
+            backend,
+            id: gist_id,
+            revision: None,
+            filename: None,
+            package: None,
+            split_modules: false,
+        },
+    );
+    config.store(dry_run)?;
+    Ok(())
+}
+
+fn config_set_token(
+    backend: Backend,
+    opt: OptScriptsConfigSetGithubToken,
+    ctx: Context<impl Sized, impl Sized, impl Sized>,
+) -> anyhow::Result<()> {
+    let (manifest_path, color, dry_run, token) = match opt {
+        OptScriptsConfigSetGithubToken::File(OptScriptsConfigSetGithubTokenFile {
+            manifest_path,
+            color,
+            dry_run,
+            path,
+        }) => (
+            manifest_path,
+            color,
+            dry_run,
+            CargoScriptsConfigToken::File { path },
+        ),
+        OptScriptsConfigSetGithubToken::Env(OptScriptsConfigSetGithubTokenEnv {
+            manifest_path,
+            color,
+            dry_run,
+            name,
+        }) => (
+            manifest_path,
+            color,
+            dry_run,
+            CargoScriptsConfigToken::Env { name },
+        ),
+        OptScriptsConfigSetGithubToken::Command(OptScriptsConfigSetGithubTokenCommand {
+            manifest_path,
+            color,
+            dry_run,
+            argv,
+        }) => (
+            manifest_path,
+            color,
+            dry_run,
+            CargoScriptsConfigToken::Command { argv },
+        ),
+    };
+
+    let Context {
+        cwd, init_logger, ..
+    } = ctx;
+
+    init_logger(color);
+
+    let cargo_metadata::Metadata { workspace_root, .. } =
+        cargo_metadata_no_deps_expecting_virtual(manifest_path.as_deref(), color, &cwd)?;
+    let mut config = CargoScriptsConfig::load(&workspace_root)?;
+    match backend {
+        Backend::Github => {
+            info!("`github_token`: {:?} → {:?}", config.github_token, token);
+            config.github_token = token;
+        }
+        Backend::Gitlab => {
+            info!("`gitlab.token`: {:?} → {:?}", config.gitlab.token, token);
+            config.gitlab.token = Some(token);
+        }
+        Backend::Git => unreachable!("there is no `config set git-token`"),
+    }
     config.store(dry_run)?;
     Ok(())
 }
@@ -1174,6 +2601,63 @@ fn config_remove_gist_id(
     Ok(())
 }
 
+fn sync_status(
+    metadata: &cargo_metadata::Metadata,
+    remote: &dyn Remote,
+    token: Option<&str>,
+    package_name: &str,
+    gist: &CargoScriptsConfigGist,
+    export_format: Option<ManifestFormat>,
+) -> anyhow::Result<SyncStatus> {
+    let package = match metadata.packages.iter().find(|p| p.name == package_name) {
+        Some(package) => package,
+        None => return Ok(SyncStatus::MissingPackage),
+    };
+    let (local, local_files) = package.export_script(export_format, gist.split_modules)?;
+
+    let remote_script = match RemoteScript::fetch(remote, token, &gist.id, None) {
+        Ok(remote) => remote,
+        Err(err) if is_not_found(&err) => return Ok(SyncStatus::MissingGist),
+        Err(err) => return Err(err),
+    };
+
+    if remote_script.has_same_content(&local, &local_files) {
+        return Ok(SyncStatus::UpToDate);
+    }
+
+    let base = match &gist.revision {
+        Some(revision) if remote_script.revision.as_ref() == Some(revision) => {
+            return Ok(SyncStatus::LocalAhead);
+        }
+        Some(revision) => match RemoteScript::fetch(remote, token, &gist.id, Some(revision)) {
+            Ok(base) => base,
+            Err(err) if is_not_found(&err) => return Ok(SyncStatus::Diverged),
+            Err(err) => return Err(err),
+        },
+        None => return Ok(SyncStatus::Diverged),
+    };
+
+    Ok(if base.has_same_content(&local, &local_files) {
+        SyncStatus::RemoteAhead
+    } else {
+        SyncStatus::Diverged
+    })
+}
+
+#[derive(Serialize, IntoStaticStr, Debug, Clone, Copy)]
+#[serde(rename_all = "kebab-case")]
+#[strum(serialize_all = "kebab-case")]
+enum SyncStatus {
+    UpToDate,
+    LocalAhead,
+    RemoteAhead,
+    Diverged,
+    MissingPackage,
+    MissingGist,
+    /// Could not be determined.
+    Error,
+}
+
 fn cargo_metadata_no_deps_expecting_virtual(
     cli_option_manifest_path: Option<&Path>,
     cli_option_color: AnsiColorChoice,
@@ -1200,7 +2684,7 @@ fn cargo_metadata_no_deps_expecting_virtual(
     if metadata
         .resolve
         .as_ref()
-        .map_or(false, |Resolve { root, .. }| root.is_some())
+        .is_some_and(|Resolve { root, .. }| root.is_some())
     {
         bail!("the target package must be a virtual manifest");
     }
@@ -1259,9 +2743,9 @@ fn modify_ws<'a>(
         };
 
         let same_paths = |value: &toml_edit::Value, target: &str| -> _ {
-            value.as_str().map_or(false, |s| {
-                workspace_root.join(s) == workspace_root.join(target)
-            })
+            value
+                .as_str()
+                .is_some_and(|s| workspace_root.join(s) == workspace_root.join(target))
         };
 
         let array = cargo_toml["workspace"][param]
@@ -1294,10 +2778,44 @@ fn modify_ws<'a>(
 fn import_script(
     workspace_root: &Path,
     script: &str,
+    files: &[(PathBuf, String)],
+    split_modules: bool,
     dry_run: bool,
+    default_package_name: impl FnOnce() -> anyhow::Result<String>,
     path: impl FnOnce(&str) -> PathBuf,
 ) -> anyhow::Result<String> {
-    let (main_rs, cargo_toml) = replace_cargo_lang_code_with_default(script)?;
+    for (file_path, _) in files {
+        if ["Cargo.toml", "src/main.rs", "src/lib.rs"]
+            .iter()
+            .any(|p| file_path == Path::new(p))
+        {
+            bail!(
+                "a file in the remote would overwrite {}",
+                file_path.display(),
+            );
+        }
+    }
+
+    let (main_rs, cargo_toml) = split_script(script, workspace_root, default_package_name)?;
+    let (main_rs, modules) = if split_modules {
+        modules::split(&main_rs)?
+    } else {
+        (main_rs, vec![])
+    };
+    let modules = modules
+        .into_iter()
+        .map(|(module_path, content)| (Path::new("src").join(module_path), content))
+        .collect::<Vec<_>>();
+    for (module_path, _) in &modules {
+        if [Path::new("src/main.rs"), Path::new("src/lib.rs")].contains(&&**module_path)
+            || files.iter().any(|(p, _)| p == module_path)
+        {
+            bail!(
+                "cannot split the modules: {} would overwrite another file",
+                module_path.display(),
+            );
+        }
+    }
 
     let package_name = toml::from_str::<CargoToml>(&cargo_toml)
         .with_context(|| "failed to parse the manifest")?
@@ -1312,218 +2830,896 @@ fn import_script(
     create_dir_all(path.join("src"), dry_run)?;
     write(path.join("src").join("main.rs"), main_rs, dry_run)?;
 
-    modify_ws(&workspace_root, Some(&*path), None, None, None, dry_run)?;
+    for (file_path, content) in files.iter().cloned().chain(modules) {
+        let file_path = path.join(file_path);
+        if let Some(parent) = file_path.parent() {
+            create_dir_all(parent, dry_run)?;
+        }
+        write(file_path, content, dry_run)?;
+    }
+
+    modify_ws(workspace_root, Some(&*path), None, None, None, dry_run)?;
     Ok(package_name)
 }
 
+/// Replaces the characters that cannot be in a package name with `_`.
+fn package_name_from_file_name(file_name: &OsStr) -> String {
+    file_name
+        .to_string_lossy()
+        .chars()
+        .map(|c| match c {
+            'a'..='z' | 'A'..='Z' | '0'..='9' | '-' | '_' => c,
+            _ => '_',
+        })
+        .collect()
+}
+
 fn replace_cargo_lang_code_with_default(code: &str) -> anyhow::Result<(String, String)> {
     return replace_cargo_lang_code(code, MANIFEST, || {
-        anyhow!("could not find the `cargo` code block")
+        anyhow!("could not find the `cargo` code block, frontmatter, or `// cargo-deps:` line")
     });
 
     static MANIFEST: &str = "# Leave blank.";
 }
 
+/// Replaces the content of the `cargo` code block with `with`.
+///
+/// Only the lines of the content are rewritten. They keep the indentation, the style of the doc
+/// comment, and the line terminator of the script.
 fn replace_cargo_lang_code(
     code: &str,
     with: &str,
     on_not_found: impl FnOnce() -> anyhow::Error,
 ) -> anyhow::Result<(String, String)> {
-    let mut code_lines = code.lines().map(Cow::from).map(Some).collect::<Vec<_>>();
-
-    let syn::File { shebang, attrs, .. } = syn::parse_file(code)?;
-    if shebang.is_some() {
-        code_lines[0] = None;
+    let cargo_lang_code = CargoLangCode::parse(code)?.with_context(on_not_found)?;
+    let CargoLangCode {
+        doc,
+        lines,
+        comments,
+        block,
+        text,
+    } = &cargo_lang_code;
+
+    let indices = cargo_lang_code.line_indices(text);
+    let content = &lines[indices.clone()];
+    if !(content.iter().all(|l| l.comment == content[0].comment)
+        || content.iter().all(|l| !comments[l.comment].is_block))
+    {
+        bail!("the `cargo` code block must be in `//!` comments or in one `/*! */` comment");
     }
 
-    let mut remove = |i: usize, start: _, end: Option<_>| {
-        let entry = &mut code_lines[i];
-        if let Some(line) = entry {
-            let first = &line[..start];
-            let second = match end {
-                Some(end) if end < line.len() => &line[end..],
-                _ => "",
-            };
-            *line = format!("{}{}", first, second).into();
+    // Follow the first non-blank line, or the opening fence.
+    let model = content
+        .iter()
+        .find(|l| !l.text.is_empty())
+        .unwrap_or(&lines[cargo_lang_code.line_indices(block).start]);
+    let prefix = &code[model.start..model.text.start];
+    let eol = line_ending(code);
+
+    let first = &content[0];
+    let last = &content[content.len() - 1];
+    let end = if with.is_empty() {
+        last.end
+    } else {
+        last.text.end
+    };
+    let replacement = with
+        .lines()
+        .map(|line| {
             if line.is_empty() {
-                *entry = None;
+                prefix.trim_end().to_owned()
+            } else {
+                format!("{}{}", prefix, line)
             }
+        })
+        .join(eol);
+
+    let mut converted_code = code.to_owned();
+    converted_code.replace_range(first.start..end, &replacement);
+    Ok((converted_code, doc[text.clone()].to_owned()))
+}
+
+/// Removes the whole `cargo` code block, and the doc comments if nothing else is left.
+fn remove_cargo_lang_code(code: &str) -> anyhow::Result<Option<String>> {
+    let cargo_lang_code = match CargoLangCode::parse(code)? {
+        Some(cargo_lang_code) => cargo_lang_code,
+        None => return Ok(None),
+    };
+    let block = cargo_lang_code.line_indices(&cargo_lang_code.block);
+    let CargoLangCode {
+        lines, comments, ..
+    } = &cargo_lang_code;
+
+    let rest_is_blank = lines
+        .iter()
+        .enumerate()
+        .all(|(i, line)| block.contains(&i) || line.text.is_empty());
+
+    let removed = if rest_is_blank {
+        comments.iter().map(|c| c.span.clone()).collect::<Vec<_>>()
+    } else {
+        // Do not leave two blank lines in a row, or a blank line at the end.
+        let is_blank = |i: usize| lines[i].text.is_empty();
+        let before = block.start.checked_sub(1);
+        let after = Some(block.end).filter(|&i| i < lines.len());
+        let mut block = block;
+        match (before, after) {
+            (_, Some(after)) if before.is_none_or(is_blank) && is_blank(after) => {
+                block.end += 1;
+            }
+            (Some(before), None) if is_blank(before) => block.start -= 1,
+            _ => {}
         }
+        lines[block].iter().map(|l| l.start..l.end).collect()
     };
 
-    let mut doc = "".to_owned();
-
-    for attr in attrs {
-        if_chain! {
-            if let Ok(meta) = attr.parse_meta();
-            if let Meta::NameValue(MetaNameValue { path, lit, .. }) = meta;
-            if path.get_ident().map_or(false, |i| i == "doc");
-            if let Lit::Str(lit_str) = lit;
-            then {
-                doc += lit_str.value().trim_start_matches(' ');
-                doc += "\n";
-
-                for tt in attr.tokens {
-                    let (start, end) = (tt.span().start(), tt.span().end());
-                    if start.line == end.line {
-                        remove(start.line - 1, start.column, Some(end.column));
-                    } else {
-                        remove(start.line - 1, start.column, None);
-                        for i in start.line..end.line - 1 {
-                            remove(i, 0, None);
-                        }
-                        remove(end.line - 1, 0, Some(end.column));
+    let mut code = code.to_owned();
+    for range in removed.into_iter().rev() {
+        code.replace_range(range, "");
+    }
+    Ok(Some(code))
+}
+
+/// The inner doc comments of a script with the `cargo` code block in them.
+///
+/// Only `//!` and `/*! */` comments are read. `#![doc = ".."]` attributes are left as they are.
+struct CargoLangCode {
+    /// The text of the doc comments with the common indentation removed.
+    doc: String,
+    /// The lines of `doc`.
+    lines: Vec<DocLine>,
+    comments: Vec<DocComment>,
+    /// The span of the code block in `doc`, including the fences.
+    block: Range<usize>,
+    /// The span of the content of the code block in `doc`.
+    text: Range<usize>,
+}
+
+/// A line of the doc comments, as spans in the script.
+struct DocLine {
+    /// Where the line starts, including the indentation and `//!`.
+    start: usize,
+    /// The text without the common indentation. Empty at the end of the line if it is blank.
+    text: Range<usize>,
+    /// Where the line ends, including the line terminator. For the first and the last line of a
+    /// `/*! */` comment, where the text ends.
+    end: usize,
+    /// The index in `CargoLangCode::comments`.
+    comment: usize,
+}
+
+struct DocComment {
+    /// The span of the comment, including the indentation and the line terminator if it occupies
+    /// whole lines.
+    span: Range<usize>,
+    is_block: bool,
+}
+
+impl CargoLangCode {
+    /// Returns `None` if there is no `cargo` code block.
+    fn parse(code: &str) -> anyhow::Result<Option<Self>> {
+        let syn::File { attrs, .. } = syn::parse_file(code)?;
+
+        let offset = |line, column| byte_offset(code, line, column);
+        let line_start = |pos: usize| code[..pos].rfind('\n').map_or(0, |i| i + 1);
+        let trim_cr = |end: usize| {
+            if code[..end].ends_with('\r') {
+                end - 1
+            } else {
+                end
+            }
+        };
+        // Includes the indentation if nothing else precedes `pos` in the line.
+        let own_line_start = |pos: usize| {
+            let start = line_start(pos);
+            if code[start..pos].trim().is_empty() {
+                start
+            } else {
+                pos
+            }
+        };
+
+        // (start, content, end, comment)
+        let mut raw_lines = vec![];
+        let mut comments = vec![];
+
+        for attr in attrs {
+            let (start, end) = (
+                attr.pound_token.spans[0].start(),
+                attr.bracket_token.span.end(),
+            );
+            let (start, end) = (
+                offset(start.line, start.column),
+                offset(end.line, end.column),
+            );
+
+            if code[start..].starts_with("//!") {
+                let comment_end = code[start..].find('\n').map_or(code.len(), |i| start + i);
+                let line_end = (comment_end + 1).min(code.len());
+                let line_start = own_line_start(start);
+                raw_lines.push((
+                    line_start,
+                    start + 3..trim_cr(comment_end),
+                    line_end,
+                    comments.len(),
+                ));
+                comments.push(DocComment {
+                    span: line_start..line_end,
+                    is_block: false,
+                });
+            } else if code[start..].starts_with("/*!") {
+                let (value_start, value_end) = (start + 3, end - 2);
+                let mut pos = value_start;
+                loop {
+                    let newline = code[pos..value_end].find('\n').map(|i| pos + i);
+                    let content = pos..trim_cr(newline.unwrap_or(value_end));
+                    let line_end = match newline {
+                        Some(newline) if pos != value_start => newline + 1,
+                        _ => content.end,
+                    };
+                    raw_lines.push((pos, content, line_end, comments.len()));
+                    match newline {
+                        Some(newline) => pos = newline + 1,
+                        None => break,
                     }
                 }
+
+                let rest = &code[end..];
+                let rest_of_line = rest.find('\n').map_or(rest, |i| &rest[..=i]);
+                let span_end = if rest_of_line.trim().is_empty() {
+                    end + rest_of_line.len()
+                } else {
+                    end
+                };
+                comments.push(DocComment {
+                    span: own_line_start(start)..span_end,
+                    is_block: true,
+                });
             }
         }
-    }
 
-    let doc_span = pulldown_cmark::Parser::new_ext(&doc, pulldown_cmark::Options::all())
-        .into_offset_iter()
-        .fold(State::None, |mut state, (event, span)| {
-            match &state {
-                State::None => {
-                    if let pulldown_cmark::Event::Start(pulldown_cmark::Tag::CodeBlock(
-                        pulldown_cmark::CodeBlockKind::Fenced(kind),
-                    )) = event
-                    {
-                        if &*kind == "cargo" {
-                            state = State::Start;
+        let indent = |s: &str| s.len() - s.trim_start_matches(&[' ', '\t'][..]).len();
+        let is_blank = |s: &str| indent(s) == s.len();
+        let common_indent = raw_lines
+            .iter()
+            .map(|(_, content, _, _)| &code[content.clone()])
+            .filter(|s| !is_blank(s))
+            .map(indent)
+            .min()
+            .unwrap_or(0);
+
+        let lines = raw_lines
+            .into_iter()
+            .map(|(start, content, end, comment)| DocLine {
+                start,
+                text: if is_blank(&code[content.clone()]) {
+                    content.end..content.end
+                } else {
+                    content.start + common_indent..content.end
+                },
+                end,
+                comment,
+            })
+            .collect::<Vec<_>>();
+
+        let doc = lines
+            .iter()
+            .map(|l| format!("{}\n", &code[l.text.clone()]))
+            .join("");
+
+        let spans = pulldown_cmark::Parser::new_ext(&doc, pulldown_cmark::Options::all())
+            .into_offset_iter()
+            .fold(State::None, |mut state, (event, span)| {
+                match &state {
+                    State::None => {
+                        if let pulldown_cmark::Event::Start(pulldown_cmark::Tag::CodeBlock(
+                            pulldown_cmark::CodeBlockKind::Fenced(kind),
+                        )) = event
+                        {
+                            if &*kind == "cargo" {
+                                state = State::Start;
+                            }
                         }
                     }
-                }
-                State::Start => {
-                    if let pulldown_cmark::Event::Text(_) = event {
-                        state = State::Text(span);
+                    State::Start => {
+                        if let pulldown_cmark::Event::Text(_) = event {
+                            state = State::Text(span);
+                        }
                     }
+                    State::Text(text) => match event {
+                        pulldown_cmark::Event::Text(_) => {
+                            state = State::Text(text.start..span.end);
+                        }
+                        pulldown_cmark::Event::End(pulldown_cmark::Tag::CodeBlock(
+                            pulldown_cmark::CodeBlockKind::Fenced(kind),
+                        )) if &*kind == "cargo" => {
+                            state = State::End(span, text.clone());
+                        }
+                        _ => {}
+                    },
+                    State::End(..) => {}
+                }
+                state
+            })
+            .end();
+
+        return Ok(spans.map(|(block, text)| Self {
+            doc,
+            lines,
+            comments,
+            block,
+            text,
+        }));
+
+        #[derive(Debug)]
+        enum State {
+            None,
+            Start,
+            Text(Range<usize>),
+            End(Range<usize>, Range<usize>),
+        }
+
+        impl State {
+            fn end(self) -> Option<(Range<usize>, Range<usize>)> {
+                match self {
+                    Self::End(block, text) => Some((block, text)),
+                    _ => None,
                 }
-                State::Text(span) => {
-                    if let pulldown_cmark::Event::End(pulldown_cmark::Tag::CodeBlock(
-                        pulldown_cmark::CodeBlockKind::Fenced(kind),
-                    )) = event
+            }
+        }
+    }
+
+    /// The indices of the lines that a non-empty `span` of `doc` covers.
+    fn line_indices(&self, span: &Range<usize>) -> Range<usize> {
+        let line_of = |pos: usize| self.doc[..pos].matches('\n').count();
+        line_of(span.start)..line_of(span.end - 1) + 1
+    }
+}
+
+/// Converts a position reported by `syn`, a 1-based line and a column in characters, into a byte
+/// offset in `code`.
+fn byte_offset(code: &str, line: usize, column: usize) -> usize {
+    let line_start = code
+        .split_inclusive('\n')
+        .take(line - 1)
+        .map(str::len)
+        .sum::<usize>();
+    line_start
+        + code[line_start..]
+            .chars()
+            .take(column)
+            .map(char::len_utf8)
+            .sum::<usize>()
+}
+
+/// `"\r\n"` if the first line of `code` ends with it.
+fn line_ending(code: &str) -> &'static str {
+    match code.find('\n') {
+        Some(i) if code[..i].ends_with('\r') => "\r\n",
+        _ => "\n",
+    }
+}
+
+/// Inserts `header` and a line terminator right after the shebang, or at the start.
+fn insert_after_shebang(code: &mut String, header: &str) {
+    let eol = line_ending(code);
+    let pos = match code.lines().next() {
+        Some(line) if line.starts_with("#!") && !line.starts_with("#![") => {
+            if !code[line.len()..].starts_with(&['\r', '\n'][..]) {
+                code.push_str(eol);
+            }
+            code.find('\n').unwrap() + 1
+        }
+        _ => 0,
+    };
+    code.insert_str(pos, &format!("{}{}", header, eol));
+}
+
+/// Splits `script` into `src/main.rs` and `Cargo.toml`.
+///
+/// - A `cargo` code block is left in `src/main.rs` with `# Leave blank.` in it.
+/// - A frontmatter block is replaced with a `// ` comment of its opening fence in `src/main.rs`
+///   since `rustc` does not accept it. The missing fields of `[package]` are taken from the
+///   template, and `package.name` is `package_name`.
+/// - A `// cargo-deps:` line is left in `src/main.rs` without the dependencies. The dependencies
+///   are added to the manifest of the template named `package_name`.
+fn split_script(
+    script: &str,
+    workspace_root: &Path,
+    package_name: impl FnOnce() -> anyhow::Result<String>,
+) -> anyhow::Result<(String, String)> {
+    let template = || {
+        read_toml_edit(
+            CargoScriptsConfig::load(workspace_root)?
+                .base_dir()
+                .join("Cargo.toml"),
+        )
+    };
+
+    match ManifestFormat::detect(script) {
+        Some(ManifestFormat::Frontmatter) => {
+            let Frontmatter {
+                block,
+                opening,
+                body,
+            } = Frontmatter::find(script).unwrap();
+            let cargo_toml = complete_package(&script[body], template, package_name)?;
+            let block_end = block.start
+                + script[block.clone()]
+                    .trim_end_matches(&['\r', '\n'][..])
+                    .len();
+            let mut main_rs = script.to_owned();
+            main_rs.replace_range(block.start..block_end, &format!("// {}", &script[opening]));
+            Ok((main_rs, cargo_toml))
+        }
+        Some(ManifestFormat::ShortDeps) => {
+            let short_deps = ShortDeps::find(script).unwrap();
+            let deps = short_deps.parse(script)?;
+            let cargo_toml = expand_short_deps(template()?, &package_name()?, &deps)?;
+            let mut main_rs = script.to_owned();
+            main_rs.replace_range(short_deps.deps, "");
+            Ok((main_rs, cargo_toml))
+        }
+        Some(ManifestFormat::DocComment) | None => replace_cargo_lang_code_with_default(script),
+    }
+}
+
+/// Embeds `cargo_toml` in `code` in `format`.
+///
+/// `format` defaults to the format that `code` already uses, or frontmatter if there is no
+/// embedded manifest in `code`, which is the case for scripts imported from frontmatter. The
+/// opening fence left by `split_script` is reused.
+///
+/// The fields that `complete_package` has added are left out.
+///
+/// The inserted lines end with the line terminator of `code`.
+fn embed_manifest(
+    code: &str,
+    cargo_toml: &str,
+    format: Option<ManifestFormat>,
+) -> anyhow::Result<String> {
+    let cargo_toml = &remove_template_fields(cargo_toml);
+    let current = ManifestFormat::detect(code);
+    let format = format.or(current).unwrap_or(ManifestFormat::Frontmatter);
+    let eol = line_ending(code);
+
+    let mut code = code.to_owned();
+    match (current, format) {
+        (Some(ManifestFormat::DocComment), ManifestFormat::DocComment) => {
+            let (replaced, _) = replace_cargo_lang_code(&code, cargo_toml, || unreachable!())?;
+            code = replaced;
+        }
+        (Some(ManifestFormat::Frontmatter), ManifestFormat::Frontmatter) => {
+            let Frontmatter { body, .. } = Frontmatter::find(&code).unwrap();
+            code.replace_range(body, &terminate_lines(cargo_toml, eol));
+        }
+        (Some(ManifestFormat::ShortDeps), ManifestFormat::ShortDeps) => {
+            let ShortDeps { deps, .. } = ShortDeps::find(&code).unwrap();
+            code.replace_range(deps, &ShortDeps::format(cargo_toml)?);
+        }
+        (Some(ManifestFormat::DocComment), format) => {
+            code = remove_cargo_lang_code(&code)?.unwrap();
+            insert_after_shebang(
+                &mut code,
+                &embedded_manifest(format, cargo_toml, eol, None)?,
+            );
+        }
+        (Some(ManifestFormat::Frontmatter), format) => {
+            let Frontmatter { block, .. } = Frontmatter::find(&code).unwrap();
+            let header = embedded_manifest(format, cargo_toml, eol, None)?;
+            code.replace_range(block, &format!("{}{}", header, eol));
+        }
+        (Some(ManifestFormat::ShortDeps), format) => {
+            let ShortDeps { line, .. } = ShortDeps::find(&code).unwrap();
+            code.replace_range(line, &embedded_manifest(format, cargo_toml, eol, None)?);
+        }
+        (None, format) => match Frontmatter::find_placeholder(&code) {
+            Some((line, fence)) if format == ManifestFormat::Frontmatter => {
+                let fence = code[fence].to_owned();
+                let block = embedded_manifest(format, cargo_toml, eol, Some(&fence))?;
+                code.replace_range(line, &block);
+            }
+            Some((line, _)) => {
+                let line_end = line.end + (code.len() - line.end).min(eol.len());
+                code.replace_range(line.start..line_end, "");
+                let header = embedded_manifest(format, cargo_toml, eol, None)?;
+                insert_after_shebang(&mut code, &header);
+            }
+            None => {
+                let header = embedded_manifest(format, cargo_toml, eol, None)?;
+                insert_after_shebang(&mut code, &header);
+            }
+        },
+    }
+    Ok(code)
+}
+
+/// The format and the content of the manifest embedded in `code`.
+///
+/// The dependencies in a `// cargo-deps:` line are returned as a `[dependencies]` table.
+fn extract_manifest(code: &str) -> anyhow::Result<Option<(ManifestFormat, String)>> {
+    Ok(match ManifestFormat::detect(code) {
+        Some(ManifestFormat::DocComment) => {
+            let CargoLangCode { doc, text, .. } = CargoLangCode::parse(code)?.unwrap();
+            Some((ManifestFormat::DocComment, doc[text].to_owned()))
+        }
+        Some(ManifestFormat::Frontmatter) => {
+            let Frontmatter { body, .. } = Frontmatter::find(code).unwrap();
+            Some((ManifestFormat::Frontmatter, code[body].to_owned()))
+        }
+        Some(ManifestFormat::ShortDeps) => {
+            let mut cargo_toml = toml_edit::Document::new();
+            cargo_toml["dependencies"] = toml_edit::table();
+            for (name, version) in ShortDeps::find(code).unwrap().parse(code)? {
+                cargo_toml["dependencies"][name] = toml_edit::value(version);
+            }
+            let cargo_toml = cargo_toml.to_string().trim_start().to_owned();
+            Some((ManifestFormat::ShortDeps, cargo_toml))
+        }
+        None => None,
+    })
+}
+
+/// `cargo_toml` in `format`, with `eol` between the lines but not at the end.
+///
+/// A frontmatter block is opened with `fence` (`---cargo` by default), lengthened if `cargo_toml`
+/// has a line of as many dashes.
+fn embedded_manifest(
+    format: ManifestFormat,
+    cargo_toml: &str,
+    eol: &str,
+    fence: Option<&str>,
+) -> anyhow::Result<String> {
+    Ok(match format {
+        ManifestFormat::DocComment => iter::once("```cargo")
+            .chain(cargo_toml.lines())
+            .chain(iter::once("```"))
+            .map(|line| {
+                if line.is_empty() {
+                    "//!".to_owned()
+                } else {
+                    format!("//! {}", line)
+                }
+            })
+            .join(eol),
+        ManifestFormat::Frontmatter => {
+            let fence = fence.unwrap_or("---cargo");
+            let info = fence.trim_start_matches('-');
+            let longest_dashes = cargo_toml
+                .lines()
+                .map(str::trim_end)
+                .filter(|line| !line.is_empty() && line.chars().all(|c| c == '-'))
+                .map(str::len)
+                .max()
+                .unwrap_or(0);
+            let dashes = "-".repeat((fence.len() - info.len()).max(longest_dashes + 1));
+            format!(
+                "{}{}{}{}{}",
+                dashes,
+                info,
+                eol,
+                terminate_lines(cargo_toml, eol),
+                dashes,
+            )
+        }
+        ManifestFormat::ShortDeps => format!("// cargo-deps:{}", ShortDeps::format(cargo_toml)?),
+    })
+}
+
+/// Ends every line of `s` with `eol`.
+fn terminate_lines(s: &str, eol: &str) -> String {
+    s.lines().map(|line| format!("{}{}", line, eol)).join("")
+}
+
+/// A frontmatter block at the start of a script.
+struct Frontmatter {
+    /// The span of the block, including the line terminator of the closing fence.
+    block: Range<usize>,
+    /// The span of the opening fence and the info string, excluding the line terminator.
+    opening: Range<usize>,
+    /// The span of the manifest.
+    body: Range<usize>,
+}
+
+impl Frontmatter {
+    /// Only a shebang and blank lines can precede the block. The info string must be empty or
+    /// `cargo`.
+    fn find(code: &str) -> Option<Self> {
+        let mut pos = 0;
+        let mut opening = None;
+        for (i, line) in code.split_inclusive('\n').enumerate() {
+            let start = pos;
+            pos += line.len();
+            let line = line.trim_end_matches(&['\r', '\n'][..]);
+
+            match opening {
+                None => {
+                    if i == 0 && line.starts_with("#!") && !line.starts_with("#![")
+                        || line.trim().is_empty()
                     {
-                        if &*kind == "cargo" {
-                            state = State::End(span.clone());
-                        }
+                        continue;
+                    }
+                    let dashes = opening_fence(line)?;
+                    opening = Some((start, start + line.len(), dashes, pos));
+                }
+                Some((block_start, opening_end, dashes, body_start)) => {
+                    let line = line.trim_end();
+                    if line.len() == dashes && line.chars().all(|c| c == '-') {
+                        return Some(Self {
+                            block: block_start..pos,
+                            opening: block_start..opening_end,
+                            body: body_start..start,
+                        });
                     }
                 }
-                State::End(_) => {}
             }
-            state
-        })
-        .end()
-        .with_context(on_not_found)?;
+        }
+        None
+    }
 
-    let with = if with.is_empty() || with.ends_with('\n') {
-        with.to_owned()
-    } else {
-        format!("{}\n", with)
-    };
+    /// The comment that `split_script` leaves in place of a block, and the opening fence in it.
+    ///
+    /// The spans exclude the line terminator.
+    fn find_placeholder(code: &str) -> Option<(Range<usize>, Range<usize>)> {
+        let mut pos = 0;
+        for (i, line) in code.split_inclusive('\n').enumerate() {
+            let start = pos;
+            pos += line.len();
+            let line = line.trim_end_matches(&['\r', '\n'][..]);
+
+            if i == 0 && line.starts_with("#!") && !line.starts_with("#![")
+                || line.trim().is_empty()
+            {
+                continue;
+            }
+            let fence = line.strip_prefix("// ")?;
+            opening_fence(fence)?;
+            let end = start + line.len();
+            return Some((start..end, end - fence.len()..end));
+        }
+        None
+    }
+}
 
-    let converted_doc = format!("{}{}{}", &doc[..doc_span.start], with, &doc[doc_span.end..]);
+/// The number of the dashes if `line` opens a frontmatter block. The info string must be empty or
+/// `cargo`.
+fn opening_fence(line: &str) -> Option<usize> {
+    let dashes = line.len() - line.trim_start_matches('-').len();
+    let info = line[dashes..].trim();
+    if dashes < 3 || !(info.is_empty() || info == "cargo") {
+        return None;
+    }
+    Some(dashes)
+}
 
-    let converted_code = shebang
-        .map(Into::into)
-        .into_iter()
-        .chain(converted_doc.lines().map(|line| {
-            if line.is_empty() {
-                "//!".into()
-            } else {
-                format!("//! {}", line).into()
+/// Fills the missing fields of `[package]` with the ones of the template, unless `cargo_toml` has
+/// `package.name`.
+///
+/// The added lines are put between `TEMPLATE_FIELDS_BEGIN` and `TEMPLATE_FIELDS_END` right after
+/// the `[package]` header, or at the end with a `[package]` header if there is none, so that
+/// `remove_template_fields` gives back `cargo_toml` as it is.
+fn complete_package(
+    cargo_toml: &str,
+    template: impl FnOnce() -> anyhow::Result<toml_edit::Document>,
+    package_name: impl FnOnce() -> anyhow::Result<String>,
+) -> anyhow::Result<String> {
+    let cargo_toml_doc = cargo_toml
+        .parse::<toml_edit::Document>()
+        .with_context(|| "failed to parse the manifest")?;
+    if cargo_toml_doc["package"]["name"].is_str() {
+        return Ok(cargo_toml.to_owned());
+    }
+
+    let template = template()?;
+    let eol = line_ending(cargo_toml);
+    let package = &cargo_toml_doc["package"];
+    let mut fields = vec![format!(
+        "name = {}",
+        toml_edit::Value::from(package_name()?),
+    )];
+    if let Some(template) = template["package"].as_table_like() {
+        for (key, item) in template.iter() {
+            if let Some(value) = item.as_value() {
+                if key != "name" && package[key].is_none() {
+                    fields.push(format!("{} = {}", key, value.to_string().trim()));
+                }
             }
-        }))
-        .chain(code_lines.into_iter().flatten())
-        .interleave_shortest(iter::repeat("\n".into()))
+        }
+    }
+    let fields = fields
+        .iter()
+        .map(|field| format!("{}{}", field, eol))
         .join("");
 
-    return Ok((converted_code, doc[doc_span].to_owned()));
-
-    #[derive(Debug)]
-    enum State {
-        None,
-        Start,
-        Text(Range<usize>),
-        End(Range<usize>),
+    let mut cargo_toml = cargo_toml.to_owned();
+    if package.is_none() {
+        if !(cargo_toml.is_empty() || cargo_toml.ends_with('\n')) {
+            cargo_toml.push_str(eol);
+        }
+        cargo_toml += &format!(
+            "{}{}[package]{}{}{}{}",
+            TEMPLATE_FIELDS_BEGIN, eol, eol, fields, TEMPLATE_FIELDS_END, eol,
+        );
+        return Ok(cargo_toml);
     }
 
-    impl State {
-        fn end(self) -> Option<Range<usize>> {
-            match self {
-                Self::End(span) => Some(span),
-                _ => None,
+    let mut pos = 0;
+    for line in cargo_toml.split_inclusive('\n') {
+        pos += line.len();
+        let header = line.split('#').next().unwrap();
+        if header.split_whitespace().join("") == "[package]" {
+            let fields = format!(
+                "{}{}{}{}{}",
+                TEMPLATE_FIELDS_BEGIN, eol, fields, TEMPLATE_FIELDS_END, eol,
+            );
+            if !line.ends_with('\n') {
+                cargo_toml.push_str(eol);
+                pos += eol.len();
             }
+            cargo_toml.insert_str(pos, &fields);
+            return Ok(cargo_toml);
         }
     }
+    bail!("`package` must be a `[package]` table to add `package.name`");
 }
 
-fn retrieve_rust_code(gist_id: &str) -> anyhow::Result<(String, String)> {
-    let url = "https://api.github.com/gists/"
-        .parse::<Url>()
-        .unwrap()
-        .join(&gist_id)?;
+/// Removes the lines that `complete_package` has added.
+fn remove_template_fields(cargo_toml: &str) -> String {
+    let mut lines = cargo_toml.split_inclusive('\n');
+    let mut ret = "".to_owned();
+    while let Some(line) = lines.next() {
+        if line.trim_end() == TEMPLATE_FIELDS_BEGIN {
+            for line in &mut lines {
+                if line.trim_end() == TEMPLATE_FIELDS_END {
+                    break;
+                }
+            }
+        } else {
+            ret += line;
+        }
+    }
+    ret
+}
 
-    info!("GET: {}", url);
-    let res = ureq::get(url.as_ref()).set("User-Agent", USER_AGENT).call();
-    raise_synthetic_error(&res)?;
-    info!("{} {}", res.status(), res.status_text());
-    ensure!(res.status() == 200, "expected 200");
+static TEMPLATE_FIELDS_BEGIN: &str = "# Added by `cargo scripts import`. Removed on export.";
+static TEMPLATE_FIELDS_END: &str = "# End of the fields added by `cargo scripts import`.";
 
-    let Gist { files, description } = serde_json::from_str(&res.into_string()?)?;
+/// A `// cargo-deps:` line among the leading comments of a script.
+struct ShortDeps {
+    /// The span of the line, excluding the line terminator.
+    line: Range<usize>,
+    /// The span after `cargo-deps:` to the end of the line.
+    deps: Range<usize>,
+}
 
-    let file = files
-        .values()
-        .filter(|GistFile { filename, .. }| {
-            [Some("rs".as_ref()), Some("crs".as_ref())].contains(&Path::new(&filename).extension())
-        })
-        .exactly_one()
-        .map_err(|err| {
-            let mut err = err.peekable();
-            if err.peek().is_some() {
-                anyhow!(
-                    "multiple Rust files: [{}]",
-                    err.format_with(", ", |GistFile { filename, .. }, f| f(&filename)),
-                )
-            } else {
-                anyhow!("no Rust files found")
+impl ShortDeps {
+    fn find(code: &str) -> Option<Self> {
+        let mut pos = 0;
+        for (i, line) in code.split_inclusive('\n').enumerate() {
+            let start = pos;
+            pos += line.len();
+            let line = line.trim_end_matches(&['\r', '\n'][..]);
+            let end = start + line.len();
+
+            if i == 0 && line.starts_with("#!") && !line.starts_with("#![") {
+                continue;
+            }
+            match line.trim_start().strip_prefix("//") {
+                Some(comment) => {
+                    if let Some(deps) = comment.trim_start().strip_prefix("cargo-deps:") {
+                        return Some(Self {
+                            line: start..end,
+                            deps: end - deps.len()..end,
+                        });
+                    }
+                }
+                None if line.trim().is_empty() => {}
+                None => break,
             }
-        })?;
+        }
+        None
+    }
 
-    if file.truncated {
-        bail!("{} is truncated", file.filename);
+    /// `<name>="<version>"` or `<name>`, which means `*`.
+    fn parse<'a>(&self, code: &'a str) -> anyhow::Result<Vec<(&'a str, &'a str)>> {
+        code[self.deps.clone()]
+            .split(',')
+            .map(str::trim)
+            .filter(|dep| !dep.is_empty())
+            .map(|dep| {
+                let (name, version) = match dep.splitn(2, '=').collect::<Vec<_>>()[..] {
+                    [name, version] => {
+                        let version = version.trim();
+                        let version = version
+                            .strip_prefix('"')
+                            .and_then(|v| v.strip_suffix('"'))
+                            .with_context(|| {
+                                format!("expected a quoted version requirement: {:?}", dep)
+                            })?;
+                        (name.trim(), version)
+                    }
+                    _ => (dep, "*"),
+                };
+                if name.is_empty()
+                    || !name
+                        .chars()
+                        .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+                {
+                    bail!("invalid dependency in `// cargo-deps:`: {:?}", dep);
+                }
+                Ok((name, version))
+            })
+            .collect()
     }
 
-    return Ok((file.content.clone(), description));
+    /// Formats `[dependencies]` of `cargo_toml` as ` <name>="<version>", ...`.
+    ///
+    /// Fails unless the rest of the manifest is only the basic fields of `[package]`, which are
+    /// dropped.
+    fn format(cargo_toml: &str) -> anyhow::Result<String> {
+        let cargo_toml = cargo_toml
+            .parse::<toml_edit::Document>()
+            .with_context(|| "failed to parse the manifest")?;
+
+        let mut deps = vec![];
+        for (key, item) in cargo_toml.iter().filter(|(_, item)| !item.is_none()) {
+            let table = item.as_table_like();
+            match key {
+                "package" => {
+                    for (field, _) in table.into_iter().flat_map(|t| t.iter()) {
+                        if !PACKAGE_FIELDS.contains(&field) {
+                            bail!("`package.{}` cannot be written in `// cargo-deps:`", field);
+                        }
+                    }
+                }
+                "dependencies" => {
+                    for (name, item) in table.into_iter().flat_map(|t| t.iter()) {
+                        let version = item
+                            .as_str()
+                            .or_else(|| {
+                                let table = item.as_table_like()?;
+                                if table.len() == 1 {
+                                    table.get("version")?.as_str()
+                                } else {
+                                    None
+                                }
+                            })
+                            .with_context(|| {
+                                format!(
+                                    "`dependencies.{}` is not a plain version requirement",
+                                    name,
+                                )
+                            })?;
+                        deps.push(format!("{}={:?}", name, version));
+                    }
+                }
+                key => bail!("`{}` cannot be written in `// cargo-deps:`", key),
+            }
+        }
 
-    #[derive(Deserialize)]
-    struct Gist {
-        files: IndexMap<String, GistFile>,
-        description: String,
-    }
+        return Ok(if deps.is_empty() {
+            "".to_owned()
+        } else {
+            format!(" {}", deps.join(", "))
+        });
 
-    #[derive(Deserialize, Debug)]
-    struct GistFile {
-        filename: String,
-        truncated: bool,
-        content: String,
+        static PACKAGE_FIELDS: &[&str] = &["name", "version", "authors", "edition", "publish"];
     }
 }
 
-static USER_AGENT: &str = "cargo-scripts <https://github.com/qryxip/cargo-scripts>";
-
-fn raise_synthetic_error(res: &Response) -> anyhow::Result<()> {
-    if let Some(err) = res.synthetic_error() {
-        let mut err = err as &dyn std::error::Error;
-        let mut displays = vec![err.to_string()];
-        while let Some(source) = err.source() {
-            displays.push(source.to_string());
-            err = source;
-        }
-        let mut displays = displays.into_iter().rev();
-        let cause = anyhow!("{}", displays.next().unwrap());
-        return Err(displays.fold(cause, |err, display| err.context(display)));
+fn expand_short_deps(
+    mut cargo_toml: toml_edit::Document,
+    package_name: &str,
+    deps: &[(&str, &str)],
+) -> anyhow::Result<String> {
+    modify_package_name(&mut cargo_toml, package_name)?;
+    if !cargo_toml["dependencies"].is_table_like() {
+        cargo_toml["dependencies"] = toml_edit::table();
     }
-    Ok(())
+    for &(name, version) in deps {
+        cargo_toml["dependencies"][name] = toml_edit::value(version);
+    }
+    Ok(cargo_toml.to_string())
 }
 
 fn read(path: impl AsRef<Path>) -> anyhow::Result<String> {
@@ -1562,6 +3758,36 @@ fn write(path: impl AsRef<Path>, contents: impl AsRef<[u8]>, dry_run: bool) -> a
     Ok(())
 }
 
+/// Same as `write` except that the file is created with owner-only permissions on Unix.
+fn write_private(
+    path: impl AsRef<Path>,
+    contents: impl AsRef<[u8]>,
+    dry_run: bool,
+) -> anyhow::Result<()> {
+    let path = path.as_ref();
+    if !dry_run {
+        let mut options = fs::OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        options
+            .open(path)
+            .and_then(|mut file| {
+                // `mode` applies only to a new file.
+                #[cfg(unix)]
+                file.set_permissions(std::os::unix::fs::PermissionsExt::from_mode(0o600))?;
+                file.write_all(contents.as_ref())
+            })
+            .with_context(|| format!("failed to write {}", path.display()))?;
+    }
+    info!(
+        "{}Wrote {}",
+        if dry_run { "[dry-run] " } else { "" },
+        path.display(),
+    );
+    Ok(())
+}
+
 fn copy(src: impl AsRef<Path>, dst: impl AsRef<Path>, dry_run: bool) -> anyhow::Result<()> {
     let (src, dst) = (src.as_ref(), dst.as_ref());
     if !dry_run {
@@ -1602,6 +3828,16 @@ impl MetadataExt for cargo_metadata::Metadata {
 
 trait PakcageExt {
     fn find_default_bin(&self) -> anyhow::Result<(&Path, String)>;
+    fn export_script(
+        &self,
+        format: Option<ManifestFormat>,
+        bundle: bool,
+    ) -> anyhow::Result<(String, Vec<(PathBuf, String)>)>;
+    fn script_files(
+        &self,
+        src_path: &Path,
+        exclude: &[PathBuf],
+    ) -> anyhow::Result<Vec<(PathBuf, String)>>;
 }
 
 impl PakcageExt for Package {
@@ -1613,7 +3849,7 @@ impl PakcageExt for Package {
             .targets
             .iter()
             .filter(|Target { kind, name, .. }| {
-                kind.contains(&"bin".to_owned()) && default_run.map_or(true, |d| d == name)
+                kind.contains(&"bin".to_owned()) && default_run.is_none_or(|d| d == name)
             })
             .exactly_one()
             .map_err(|err| match err.count() {
@@ -1623,42 +3859,95 @@ impl PakcageExt for Package {
 
         Ok((src_path, cargo_toml_str))
     }
+
+    /// With `bundle`, the modules in other files are inlined into the script instead of being
+    /// included as files.
+    fn export_script(
+        &self,
+        format: Option<ManifestFormat>,
+        bundle: bool,
+    ) -> anyhow::Result<(String, Vec<(PathBuf, String)>)> {
+        let (src_path, cargo_toml) = self.find_default_bin()?;
+        let (code, inlined) = if bundle {
+            modules::bundle(src_path)?
+        } else {
+            (read(src_path)?, vec![])
+        };
+        let script = embed_manifest(&code, &cargo_toml, format)?;
+        Ok((script, self.script_files(src_path, &inlined)?))
+    }
+
+    fn script_files(
+        &self,
+        src_path: &Path,
+        exclude: &[PathBuf],
+    ) -> anyhow::Result<Vec<(PathBuf, String)>> {
+        let package_dir = self
+            .manifest_path
+            .parent()
+            .expect("`manifest_path` should end with \"Cargo.toml\"");
+
+        let mut files = vec![];
+        for entry in WalkBuilder::new(package_dir).build() {
+            match entry {
+                Ok(entry) => {
+                    let path = entry.path();
+                    if !(path.is_dir()
+                        || path == src_path
+                        || exclude.iter().any(|p| p == path)
+                        || path == self.manifest_path
+                        || path == package_dir.join("Cargo.lock")
+                        || path.starts_with(package_dir.join("target")))
+                    {
+                        let relative = path.strip_prefix(package_dir)?.to_owned();
+                        let content = fs::read(path)
+                            .with_context(|| format!("failed to read {}", path.display()))?;
+                        match String::from_utf8(content) {
+                            Ok(content) => files.push((relative, content)),
+                            Err(_) => warn!("Skipping {}: not valid UTF-8", path.display()),
+                        }
+                    }
+                }
+                Err(err) => warn!("{}", err),
+            }
+        }
+        files.sort();
+        Ok(files)
+    }
 }
 
 #[derive(Deserialize, Serialize, Debug)]
 struct CargoScriptsConfig {
     base: String,
-    github_token: CargoScriptsConfigGithubToken,
+    github_token: CargoScriptsConfigToken,
     #[serde(default)]
-    gist_ids: BTreeMap<String, String>,
+    gist_ids: BTreeMap<String, CargoScriptsConfigGist>,
+    #[serde(default, skip_serializing_if = "CargoScriptsConfigGithub::is_default")]
+    github: CargoScriptsConfigGithub,
+    #[serde(default, skip_serializing_if = "CargoScriptsConfigGitlab::is_default")]
+    gitlab: CargoScriptsConfigGitlab,
+    #[serde(default, skip_serializing_if = "CargoScriptsConfigGit::is_default")]
+    git: CargoScriptsConfigGit,
+    #[serde(default, skip_serializing_if = "CargoScriptsConfigHttp::is_default")]
+    http: CargoScriptsConfigHttp,
+    /// The default of `export --format`, also used for `gist push`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    export_format: Option<ManifestFormat>,
     #[serde(skip)]
     path: PathBuf,
 }
 
 impl CargoScriptsConfig {
     fn new(path: &Path, home_dir: Option<&Path>) -> anyhow::Result<Self> {
-        let github_token = CargoScriptsConfigGithubToken::File {
-            path: {
-                let path = dirs::data_local_dir()
-                    .with_context(|| "local data directory not found")?
-                    .join("cargo-scripts")
-                    .join("github-token")
-                    .into_os_string()
-                    .into_string()
-                    .map_err(|s| anyhow!("{:?} is not valid UTF-8", s))?;
-                let home_dir = shellexpand::tilde_with_context("~", || home_dir);
-                if !home_dir.is_empty() && path.starts_with(&*home_dir) {
-                    format!("~{}", path.trim_start_matches(&*home_dir))
-                } else {
-                    path
-                }
-            },
-        };
-
         Ok(Self {
             base: "./template".to_owned(),
-            github_token,
+            github_token: CargoScriptsConfigToken::default_file("github-token", home_dir)?,
             gist_ids: BTreeMap::new(),
+            github: CargoScriptsConfigGithub::default(),
+            gitlab: CargoScriptsConfigGitlab::default(),
+            git: CargoScriptsConfigGit::default(),
+            http: CargoScriptsConfigHttp::default(),
+            export_format: None,
             path: path.to_owned(),
         })
     }
@@ -1669,39 +3958,593 @@ impl CargoScriptsConfig {
         Ok(Self { path, ..this })
     }
 
+    /// `base` resolved from the workspace root.
+    fn base_dir(&self) -> PathBuf {
+        let workspace_root = self.path.parent().unwrap_or_else(|| ".".as_ref());
+        let base = Path::new(&self.base);
+        workspace_root.join(base.strip_prefix(".").unwrap_or(base))
+    }
+
     fn store(&self, dry_run: bool) -> anyhow::Result<()> {
-        write(&self.path, &toml::to_string(self).unwrap(), dry_run)
+        // Go through `toml::Value` so that plain values are emitted before tables.
+        let value = toml::Value::try_from(self).unwrap();
+        write(&self.path, toml::to_string(&value).unwrap(), dry_run)
+    }
+
+    /// - GitHub: `$CARGO_SCRIPTS_GITHUB_API_URL` > `github.api_url` > `https://api.github.com`
+    /// - GitLab: `$CARGO_SCRIPTS_GITLAB_API_URL` > `gitlab.api_url` > `https://gitlab.com/api/v4`
+    /// - git: `git.url` (required)
+    ///
+    /// With `offline`, gists are retrieved from the cache. The other backends do not support it.
+    fn remote(&self, backend: Backend, offline: bool) -> anyhow::Result<Box<dyn Remote>> {
+        if offline && backend != Backend::Github {
+            bail!("`--offline` is only supported for GitHub gists");
+        }
+
+        let (env_var, api_url, default) = match backend {
+            Backend::Github => (
+                "CARGO_SCRIPTS_GITHUB_API_URL",
+                &self.github.api_url,
+                "https://api.github.com",
+            ),
+            Backend::Gitlab => (
+                "CARGO_SCRIPTS_GITLAB_API_URL",
+                &self.gitlab.api_url,
+                "https://gitlab.com/api/v4",
+            ),
+            Backend::Git => return self.git.remote(&self.path),
+        };
+
+        let base = match env::var(env_var) {
+            Ok(api_url) => api_url
+                .parse()
+                .with_context(|| format!("invalid URL in `${}`: {:?}", env_var, api_url))?,
+            Err(env::VarError::NotUnicode(api_url)) => {
+                bail!("`${}` is not valid UTF-8: {:?}", env_var, api_url)
+            }
+            Err(env::VarError::NotPresent) => {
+                api_url.clone().unwrap_or_else(|| default.parse().unwrap())
+            }
+        };
+        Ok(match backend {
+            Backend::Github => Box::new(remote::Github::new(
+                base,
+                self.http.client(http::Service::Github)?,
+                dirs::cache_dir().map(|dir| dir.join("cargo-scripts").join("gists")),
+                offline,
+            )),
+            Backend::Gitlab => Box::new(remote::Gitlab::new(
+                base,
+                self.http.client(http::Service::Gitlab)?,
+            )),
+            Backend::Git => unreachable!(),
+        })
+    }
+
+    /// `gitlab.token` defaults to `<local data dir>/cargo-scripts/gitlab-token`.
+    ///
+    /// `None` for git, which uses its own credentials.
+    fn token(
+        &self,
+        backend: Backend,
+        home_dir: Option<&Path>,
+    ) -> anyhow::Result<Option<Cow<'_, CargoScriptsConfigToken>>> {
+        match (backend, &self.gitlab.token) {
+            (Backend::Github, _) => Ok(Some(Cow::Borrowed(&self.github_token))),
+            (Backend::Gitlab, Some(token)) => Ok(Some(Cow::Borrowed(token))),
+            (Backend::Gitlab, None) => {
+                CargoScriptsConfigToken::default_file("gitlab-token", home_dir)
+                    .map(|token| Some(Cow::Owned(token)))
+            }
+            (Backend::Git, _) => Ok(None),
+        }
+    }
+
+    /// Builds the remotes of `backends` with the tokens read from stdin (`--token-stdin`) or the
+    /// ones that are available without asking.
+    fn remotes(
+        &self,
+        backends: impl IntoIterator<Item = Backend>,
+        token_stdin: bool,
+        home_dir: Option<&Path>,
+        read_input: impl FnOnce() -> io::Result<String>,
+        no_auth: bool,
+        offline: bool,
+    ) -> anyhow::Result<Remotes<Option<String>>> {
+        let backends = backends.into_iter().collect::<BTreeSet<_>>();
+        if token_stdin && backends.iter().filter(|&&b| b != Backend::Git).count() > 1 {
+            bail!("`--token-stdin` cannot be used with multiple backends");
+        }
+        let mut read_input = Some(read_input).filter(|_| token_stdin);
+
+        backends
+            .into_iter()
+            .map(|backend| {
+                let remote = self.remote(backend, offline)?;
+                let read_input = match backend {
+                    Backend::Git => None,
+                    _ => read_input.take(),
+                };
+                let token = match read_input {
+                    Some(read_input) => Some(read_token(read_input)?),
+                    None => self.load_token(backend, home_dir, no_auth)?,
+                };
+                Ok((backend, (remote, token)))
+            })
+            .collect()
+    }
+
+    /// `remotes` for one backend.
+    fn remote_with_optional_token(
+        &self,
+        backend: Backend,
+        token_stdin: bool,
+        home_dir: Option<&Path>,
+        read_input: impl FnOnce() -> io::Result<String>,
+        no_auth: bool,
+        offline: bool,
+    ) -> anyhow::Result<(Box<dyn Remote>, Option<String>)> {
+        let mut remotes = self.remotes(
+            iter::once(backend),
+            token_stdin,
+            home_dir,
+            read_input,
+            no_auth,
+            offline,
+        )?;
+        Ok(remotes.remove(&backend).expect("should contain `backend`"))
+    }
+
+    /// Loads the token of `backend` without asking.
+    ///
+    /// This is for reading, so a token that fails to load is skipped with a warning.
+    fn load_token(
+        &self,
+        backend: Backend,
+        home_dir: Option<&Path>,
+        no_auth: bool,
+    ) -> anyhow::Result<Option<String>> {
+        if no_auth {
+            return Ok(None);
+        }
+        let token = self
+            .token(backend, home_dir)
+            .and_then(|token| token.map(|token| token.load(home_dir)).transpose());
+        match token {
+            Ok(token) => Ok(token.flatten()),
+            Err(err) => {
+                warn!(
+                    "Could not load the {} token. Sending the requests without it: {:#}",
+                    <&str>::from(backend),
+                    err,
+                );
+                Ok(None)
+            }
+        }
+    }
+
+    /// Builds the remotes of `backends` with the tokens read from stdin (`--token-stdin`) or
+    /// loaded with `CargoScriptsConfigToken::load_or_ask`.
+    fn remotes_with_tokens(
+        &self,
+        backends: impl IntoIterator<Item = Backend>,
+        token_stdin: bool,
+        dry_run: bool,
+        home_dir: Option<&Path>,
+        read_input: impl FnOnce() -> io::Result<String>,
+        mut read_password: impl FnMut(&str) -> io::Result<String>,
+    ) -> anyhow::Result<Remotes<String>> {
+        let backends = backends.into_iter().collect::<BTreeSet<_>>();
+        if token_stdin && backends.iter().filter(|&&b| b != Backend::Git).count() > 1 {
+            bail!("`--token-stdin` cannot be used with multiple backends");
+        }
+        let mut read_input = Some(read_input).filter(|_| token_stdin);
+
+        backends
+            .into_iter()
+            .map(|backend| {
+                let remote = self.remote(backend, false)?;
+                let token = match self.token(backend, home_dir)? {
+                    None => "".to_owned(),
+                    Some(token) => match read_input.take() {
+                        Some(read_input) => read_token(read_input)?,
+                        None => {
+                            token.load_or_ask(&*remote, dry_run, home_dir, &mut read_password)?
+                        }
+                    },
+                };
+                Ok((backend, (remote, token)))
+            })
+            .collect()
+    }
+
+    /// `remotes_with_tokens` for one backend.
+    fn remote_with_token(
+        &self,
+        backend: Backend,
+        token_stdin: bool,
+        dry_run: bool,
+        home_dir: Option<&Path>,
+        read_input: impl FnOnce() -> io::Result<String>,
+        read_password: impl FnMut(&str) -> io::Result<String>,
+    ) -> anyhow::Result<(Box<dyn Remote>, String)> {
+        let mut remotes = self.remotes_with_tokens(
+            iter::once(backend),
+            token_stdin,
+            dry_run,
+            home_dir,
+            read_input,
+            read_password,
+        )?;
+        Ok(remotes.remove(&backend).expect("should contain `backend`"))
     }
 }
 
-#[derive(Deserialize, Serialize, Debug)]
+/// The remote and the token for each backend.
+type Remotes<T> = BTreeMap<Backend, (Box<dyn Remote>, T)>;
+
+/// `"<id>"` or `{ backend = "<backend>", id = "<id>", revision = "<last synced revision>" }`
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+#[serde(
+    from = "CargoScriptsConfigGistRepr",
+    into = "CargoScriptsConfigGistRepr"
+)]
+struct CargoScriptsConfigGist {
+    backend: Backend,
+    id: String,
+    revision: Option<String>,
+    /// Name of the file that contains the script.
+    filename: Option<String>,
+    /// Name of the package when `filename` was recorded. The file is renamed on push if the package
+    /// has been renamed since.
+    package: Option<String>,
+    /// Whether the package was cloned with `--split-modules`. The modules are inlined into the
+    /// script on push, and moved into files again on pull.
+    split_modules: bool,
+}
+
+#[derive(Deserialize, Serialize)]
+#[serde(untagged)]
+enum CargoScriptsConfigGistRepr {
+    Id(String),
+    Table {
+        #[serde(default, skip_serializing_if = "Backend::is_default")]
+        backend: Backend,
+        id: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        revision: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        filename: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        package: Option<String>,
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        split_modules: bool,
+    },
+}
+
+impl CargoScriptsConfigGist {
+    /// The filename of the script in the gist, and the one to push the script as.
+    ///
+    /// The recorded filename (or `fetched_filename` if none is recorded) is kept unless the package
+    /// has been renamed since, in which case the script is renamed to `<package_name>.rs`.
+    fn script_filenames(&self, fetched_filename: &str, package_name: &str) -> (String, String) {
+        let remote_filename = self
+            .filename
+            .clone()
+            .unwrap_or_else(|| fetched_filename.to_owned());
+        let script_filename = match &self.package {
+            Some(last_package_name) if last_package_name != package_name => {
+                format!("{}.rs", package_name)
+            }
+            _ => remote_filename.clone(),
+        };
+        (remote_filename, script_filename)
+    }
+}
+
+impl From<CargoScriptsConfigGistRepr> for CargoScriptsConfigGist {
+    fn from(repr: CargoScriptsConfigGistRepr) -> Self {
+        match repr {
+            CargoScriptsConfigGistRepr::Id(id) => Self {
+                backend: Backend::default(),
+                id,
+                revision: None,
+                filename: None,
+                package: None,
+                split_modules: false,
+            },
+            CargoScriptsConfigGistRepr::Table {
+                backend,
+                id,
+                revision,
+                filename,
+                package,
+                split_modules,
+            } => Self {
+                backend,
+                id,
+                revision,
+                filename,
+                package,
+                split_modules,
+            },
+        }
+    }
+}
+
+impl From<CargoScriptsConfigGist> for CargoScriptsConfigGistRepr {
+    fn from(gist: CargoScriptsConfigGist) -> Self {
+        match gist {
+            CargoScriptsConfigGist {
+                backend: Backend::Github,
+                id,
+                revision: None,
+                filename: None,
+                package: None,
+                split_modules: false,
+            } => Self::Id(id),
+            CargoScriptsConfigGist {
+                backend,
+                id,
+                revision,
+                filename,
+                package,
+                split_modules,
+            } => Self::Table {
+                backend,
+                id,
+                revision,
+                filename,
+                package,
+                split_modules,
+            },
+        }
+    }
+}
+
+#[derive(
+    Default,
+    Deserialize,
+    Serialize,
+    EnumString,
+    EnumVariantNames,
+    IntoStaticStr,
+    Debug,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+)]
+#[serde(rename_all = "kebab-case")]
+#[strum(serialize_all = "kebab-case")]
+pub enum Backend {
+    /// GitHub Gist
+    #[default]
+    Github,
+    /// GitLab Snippets
+    Gitlab,
+    /// A plain git repository (`[git]`)
+    Git,
+}
+
+impl Backend {
+    fn is_default(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+#[derive(Default, Deserialize, Serialize, Debug, PartialEq)]
+struct CargoScriptsConfigGithub {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    api_url: Option<Url>,
+}
+
+impl CargoScriptsConfigGithub {
+    fn is_default(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+#[derive(Default, Deserialize, Serialize, Debug, PartialEq)]
+struct CargoScriptsConfigGitlab {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    api_url: Option<Url>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    token: Option<CargoScriptsConfigToken>,
+}
+
+impl CargoScriptsConfigGitlab {
+    fn is_default(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+#[derive(Default, Deserialize, Serialize, Debug, PartialEq)]
+struct CargoScriptsConfigGit {
+    /// URL or path of the repository. A relative path is resolved from the workspace root.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    url: Option<String>,
+    /// Defaults to `main`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    branch: Option<String>,
+    /// Directory in the repository to create new scripts in. Defaults to the root.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    dir: Option<String>,
+}
+
+impl CargoScriptsConfigGit {
+    fn is_default(&self) -> bool {
+        *self == Self::default()
+    }
+
+    fn remote(&self, config_path: &Path) -> anyhow::Result<Box<dyn Remote>> {
+        let url = self
+            .url
+            .clone()
+            .with_context(|| format!("`git.url` is not set in {}", config_path.display()))?;
+        let cwd = config_path.parent().unwrap_or_else(|| ".".as_ref());
+        Ok(Box::new(remote::Git::new(
+            url,
+            self.branch.clone().unwrap_or_else(|| "main".to_owned()),
+            self.dir.clone().unwrap_or_default(),
+            cwd.to_owned(),
+        )))
+    }
+}
+
+#[derive(Default, Deserialize, Serialize, Debug, PartialEq)]
+struct CargoScriptsConfigHttp {
+    /// In seconds.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    connect_timeout: Option<u64>,
+    /// In seconds.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    read_timeout: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    proxy: Option<String>,
+    /// Path to a PEM file of extra CA certificates.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    cainfo: Option<PathBuf>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    retries: Option<u32>,
+}
+
+impl CargoScriptsConfigHttp {
+    fn is_default(&self) -> bool {
+        *self == Self::default()
+    }
+
+    /// `http.proxy` > `$HTTPS_PROXY` > `$https_proxy`
+    fn client(&self, service: http::Service) -> anyhow::Result<http::Client> {
+        let proxy = match &self.proxy {
+            Some(proxy) => Some(proxy.clone()),
+            None => env::var("HTTPS_PROXY")
+                .or_else(|_| env::var("https_proxy"))
+                .ok()
+                .filter(|proxy| !proxy.is_empty()),
+        };
+        http::Client::new(
+            service,
+            Duration::from_secs(self.connect_timeout.unwrap_or(30)),
+            Duration::from_secs(self.read_timeout.unwrap_or(30)),
+            proxy.as_deref(),
+            self.cainfo.as_deref(),
+            self.retries.unwrap_or(3),
+        )
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
 #[serde(tag = "kind")]
-enum CargoScriptsConfigGithubToken {
+enum CargoScriptsConfigToken {
     File { path: String },
+    Env { name: String },
+    Command { argv: Vec<String> },
 }
 
-impl CargoScriptsConfigGithubToken {
+impl CargoScriptsConfigToken {
+    /// `<local data dir>/cargo-scripts/<name>`, with the home directory written as `~`.
+    fn default_file(name: &str, home_dir: Option<&Path>) -> anyhow::Result<Self> {
+        let path = dirs::data_local_dir()
+            .with_context(|| "local data directory not found")?
+            .join("cargo-scripts")
+            .join(name)
+            .into_os_string()
+            .into_string()
+            .map_err(|s| anyhow!("{:?} is not valid UTF-8", s))?;
+        let home_dir = shellexpand::tilde_with_context("~", || home_dir);
+        let path = if !home_dir.is_empty() && path.starts_with(&*home_dir) {
+            format!("~{}", path.trim_start_matches(&*home_dir))
+        } else {
+            path
+        };
+        Ok(Self::File { path })
+    }
+
+    /// Loads the token without asking. Returns `None` if it is not available.
+    fn load(&self, home_dir: Option<&Path>) -> anyhow::Result<Option<String>> {
+        match self {
+            Self::File { path } => {
+                let path = shellexpand::tilde_with_context(path, || home_dir);
+                if Path::new(&*path).exists() {
+                    #[cfg(unix)]
+                    warn_if_not_private(&path)?;
+                    read(&*path).map(Some)
+                } else {
+                    Ok(None)
+                }
+            }
+            Self::Env { name } => match env::var(name) {
+                Ok(token) => Ok(Some(token)),
+                Err(env::VarError::NotPresent) => Ok(None),
+                Err(env::VarError::NotUnicode(_)) => bail!("`${}` is not valid UTF-8", name),
+            },
+            Self::Command { argv } => {
+                let (program, args) = argv.split_first().with_context(|| "`argv` is empty")?;
+                info_cmd(program, args);
+                let token = duct::cmd(program, args).read()?;
+                Ok(Some(token).filter(|token| !token.is_empty()))
+            }
+        }
+    }
+
     fn load_or_ask(
         &self,
+        remote: &dyn Remote,
         dry_run: bool,
         home_dir: Option<&Path>,
         mut ask: impl FnMut(&str) -> io::Result<String>,
     ) -> anyhow::Result<String> {
-        let Self::File { path } = self;
-        let path = shellexpand::tilde_with_context(path, || home_dir);
-        if Path::new(&*path).exists() {
-            read(&*path)
-        } else {
-            let token = ask("GitHub token: ")?;
-            if let Some(parent) = Path::new(&*path).parent() {
-                create_dir_all(parent, dry_run)?;
+        if let Some(token) = self.load(home_dir)? {
+            return Ok(token);
+        }
+        match self {
+            Self::File { path } => {
+                let path = shellexpand::tilde_with_context(path, || home_dir);
+                let token = ask(&format!("{} token: ", remote.name()))?;
+                remote.check_token(&token)?;
+                if let Some(parent) = Path::new(&*path).parent() {
+                    create_dir_all(parent, dry_run)?;
+                }
+                write_private(&*path, &token, dry_run)?;
+                Ok(token)
             }
-            write(&*path, &token, dry_run)?;
-            Ok(token)
+            Self::Env { name } => bail!("`${}` is not present", name),
+            Self::Command { argv } => bail!("{:?} printed nothing", argv),
         }
     }
 }
 
+/// Warns if the token file is accessible by other users. Returns whether it has warned.
+#[cfg(unix)]
+fn warn_if_not_private(path: &str) -> anyhow::Result<bool> {
+    use std::os::unix::fs::PermissionsExt as _;
+
+    let mode = fs::metadata(path)
+        .with_context(|| format!("failed to get the metadata of {}", path))?
+        .permissions()
+        .mode();
+    let accessible = mode & 0o077 != 0;
+    if accessible {
+        warn!(
+            "{} is accessible by other users (mode {:o}). Run `chmod 600 {}`",
+            path,
+            mode & 0o777,
+            path,
+        );
+    }
+    Ok(accessible)
+}
+
+fn read_token(read_input: impl FnOnce() -> io::Result<String>) -> anyhow::Result<String> {
+    let token = read_input()?;
+    let token = token.trim();
+    ensure!(!token.is_empty(), "no token was given to stdin");
+    Ok(token.to_owned())
+}
+
 #[derive(Deserialize)]
 struct CargoToml {
     #[serde(default)]
@@ -1716,3 +4559,425 @@ struct CargoTomlPackage {
     #[serde(default)]
     default_run: Option<String>,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        embed_manifest, expand_short_deps, extract_manifest, merge, split_script, Backend,
+        CargoScriptsConfig, CargoScriptsConfigGist, CargoScriptsConfigToken, ManifestFormat,
+        MergeSide, ShortDeps,
+    };
+
+    use std::fs;
+    use std::path::Path;
+
+    /// Importing then exporting each script in `tests/round-trip` gives back the same bytes, also
+    /// with CRLF and without the trailing newline.
+    #[test]
+    fn round_trip() -> anyhow::Result<()> {
+        let workspace = tempfile::Builder::new()
+            .prefix("cargo-scripts-")
+            .tempdir()?;
+        let workspace_root = workspace.path();
+        CargoScriptsConfig::new(&workspace_root.join("cargo-scripts.toml"), None)?.store(false)?;
+        fs::create_dir(workspace_root.join("template"))?;
+        fs::write(workspace_root.join("template").join("Cargo.toml"), TEMPLATE)?;
+
+        let corpus = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("tests")
+            .join("round-trip");
+        for entry in fs::read_dir(corpus)? {
+            let path = entry?.path();
+            let lf = fs::read_to_string(&path)?.replace("\r\n", "\n");
+            let crlf = lf.replace('\n', "\r\n");
+
+            for (variant, script) in &[
+                ("LF", &*lf),
+                ("LF without the trailing newline", lf.trim_end()),
+                ("CRLF", &*crlf),
+                ("CRLF without the trailing newline", crlf.trim_end()),
+            ] {
+                let (main_rs, cargo_toml) =
+                    split_script(script, workspace_root, || Ok("short-deps".to_owned()))?;
+                assert_eq!(
+                    embed_manifest(&main_rs, &cargo_toml, None)?,
+                    *script,
+                    "{} ({})",
+                    path.display(),
+                    variant,
+                );
+            }
+        }
+        return Ok(());
+
+        static TEMPLATE: &str = r#"[package]
+name = "template"
+version = "0.0.0"
+edition = "2018"
+
+[dependencies]
+"#;
+    }
+
+    #[test]
+    fn merge_clean() {
+        let base = "a\nb\nc\nd\ne\n";
+        let ours = "A\nb\nc\nd\ne\n";
+        let theirs = "a\nb\nc\nd\nE\n";
+        assert_eq!(
+            merge(Some(base), ours, theirs, None).as_deref(),
+            Some("A\nb\nc\nd\nE\n"),
+        );
+        assert_eq!(
+            merge(Some(base), base, theirs, None).as_deref(),
+            Some(theirs)
+        );
+        assert_eq!(merge(Some(base), ours, base, None).as_deref(), Some(ours));
+        assert_eq!(merge(None, ours, ours, None).as_deref(), Some(ours));
+    }
+
+    #[test]
+    fn merge_conflict() {
+        let (base, ours, theirs) = ("a\nb\nc\n", "a\nB\nc\n", "a\nβ\nc\n");
+        assert_eq!(merge(Some(base), ours, theirs, None), None);
+        assert_eq!(merge(None, ours, theirs, None), None);
+    }
+
+    #[test]
+    fn merge_ours() {
+        let (base, ours, theirs) = ("a\nb\nc\n", "a\nB\nc\n", "a\nβ\nc\n");
+        assert_eq!(
+            merge(Some(base), ours, theirs, Some(MergeSide::Ours)).as_deref(),
+            Some(ours),
+        );
+        assert_eq!(
+            merge(None, ours, theirs, Some(MergeSide::Ours)).as_deref(),
+            Some(ours),
+        );
+    }
+
+    #[test]
+    fn merge_theirs() {
+        let (base, ours, theirs) = ("a\nb\nc\n", "a\nB\nc\n", "a\nβ\nc\n");
+        assert_eq!(
+            merge(Some(base), ours, theirs, Some(MergeSide::Theirs)).as_deref(),
+            Some(theirs),
+        );
+        assert_eq!(
+            merge(None, ours, theirs, Some(MergeSide::Theirs)).as_deref(),
+            Some(theirs),
+        );
+        // A clean merge does not take either side.
+        assert_eq!(
+            merge(
+                Some("a\nb\nc\nd\ne\n"),
+                "A\nb\nc\nd\ne\n",
+                "a\nb\nc\nd\nE\n",
+                Some(MergeSide::Theirs),
+            )
+            .as_deref(),
+            Some("A\nb\nc\nd\nE\n"),
+        );
+    }
+
+    /// Only the conflicting hunks are taken from one side.
+    #[test]
+    fn merge_hunks() {
+        let base = "a\nb\nc\nd\ne\nf\ng";
+        let ours = "A\nb\nc\nd\ne\nF\ng";
+        let theirs = "a\nb\nc\nd\nE\nφ\nγ";
+        assert_eq!(merge(Some(base), ours, theirs, None), None);
+        assert_eq!(
+            merge(Some(base), ours, theirs, Some(MergeSide::Ours)).as_deref(),
+            Some("A\nb\nc\nd\ne\nF\ng"),
+        );
+        assert_eq!(
+            merge(Some(base), ours, theirs, Some(MergeSide::Theirs)).as_deref(),
+            Some("A\nb\nc\nd\nE\nφ\nγ"),
+        );
+
+        // The content that looks like a conflict marker is kept.
+        let base = "a\n=======\nb\n";
+        let ours = "a\n=======\nB\n";
+        let theirs = "a\n=======\nβ\n";
+        assert_eq!(
+            merge(Some(base), ours, theirs, Some(MergeSide::Ours)).as_deref(),
+            Some(ours),
+        );
+        assert_eq!(
+            merge(Some(base), ours, theirs, Some(MergeSide::Theirs)).as_deref(),
+            Some(theirs),
+        );
+    }
+
+    #[test]
+    fn short_deps_find() {
+        fn find(code: &str) -> Option<(&str, &str)> {
+            ShortDeps::find(code).map(|d| (&code[d.line], &code[d.deps]))
+        }
+
+        assert_eq!(
+            find("// cargo-deps: a, b=\"1\"\nfn main() {}\n"),
+            Some(("// cargo-deps: a, b=\"1\"", " a, b=\"1\"")),
+        );
+        assert_eq!(
+            find("#!/usr/bin/env run-cargo-script\r\n\r\n  //cargo-deps:a\r\n"),
+            Some(("  //cargo-deps:a", "a")),
+        );
+        assert_eq!(
+            find("// comment\n// cargo-deps: a\n"),
+            Some(("// cargo-deps: a", " a"))
+        );
+        assert_eq!(find("#![allow(unused)]\n// cargo-deps: a\n"), None);
+        assert_eq!(find("fn main() {}\n// cargo-deps: a\n"), None);
+        assert_eq!(find("/* cargo-deps: a */\n"), None);
+    }
+
+    #[test]
+    fn short_deps_parse() -> anyhow::Result<()> {
+        let parse = |deps: &str| {
+            let code = format!("// cargo-deps:{}\n", deps);
+            let short_deps = ShortDeps::find(&code).unwrap();
+            short_deps.parse(&code).map(|deps| {
+                deps.into_iter()
+                    .map(|(n, v)| (n.to_owned(), v.to_owned()))
+                    .collect::<Vec<_>>()
+            })
+        };
+        let owned = |deps: &[(&str, &str)]| {
+            deps.iter()
+                .map(|&(n, v)| (n.to_owned(), v.to_owned()))
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            parse(r#" rand="0.7", itertools , num_traits = "^0.2","#)?,
+            owned(&[("rand", "0.7"), ("itertools", "*"), ("num_traits", "^0.2")]),
+        );
+        assert_eq!(parse("")?, owned(&[]));
+
+        let err = parse(" rand=0.7").unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("expected a quoted version requirement"),
+            "{}",
+            err,
+        );
+        let err = parse(r#" ra nd="0.7""#).unwrap_err();
+        assert!(err.to_string().contains("invalid dependency"), "{}", err);
+        let err = parse(r#" ="0.7""#).unwrap_err();
+        assert!(err.to_string().contains("invalid dependency"), "{}", err);
+        Ok(())
+    }
+
+    #[test]
+    fn short_deps_format() -> anyhow::Result<()> {
+        assert_eq!(
+            ShortDeps::format(
+                r#"[package]
+name = "a"
+version = "0.0.0"
+edition = "2018"
+
+[dependencies]
+rand = "0.7"
+itertools = { version = "0.9" }
+"#,
+            )?,
+            r#" rand="0.7", itertools="0.9""#,
+        );
+        assert_eq!(ShortDeps::format("[package]\nname = \"a\"\n")?, "");
+
+        let err = ShortDeps::format("[dev-dependencies]\nrand = \"0.7\"\n").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "`dev-dependencies` cannot be written in `// cargo-deps:`",
+        );
+        let err = ShortDeps::format("[package]\nname = \"a\"\nbuild = \"build.rs\"\n").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "`package.build` cannot be written in `// cargo-deps:`",
+        );
+        let err =
+            ShortDeps::format("[dependencies]\nrand = { version = \"0.7\", features = [] }\n")
+                .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "`dependencies.rand` is not a plain version requirement",
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn expand_short_deps_into_template() -> anyhow::Result<()> {
+        let template = r#"[package]
+name = "template"
+version = "0.0.0"
+edition = "2018"
+
+[dependencies]
+itertools = "0.9"
+"#
+        .parse()?;
+        let expanded = expand_short_deps(template, "a", &[("rand", "0.7"), ("itertools", "*")])?;
+        let expanded = expanded.parse::<toml::Value>()?;
+        assert_eq!(expanded["package"]["name"].as_str(), Some("a"));
+        assert_eq!(expanded["dependencies"]["rand"].as_str(), Some("0.7"));
+        assert_eq!(expanded["dependencies"]["itertools"].as_str(), Some("*"));
+
+        let expanded =
+            expand_short_deps("[package]\nname = \"t\"\n".parse()?, "a", &[("rand", "*")])?;
+        let expanded = expanded.parse::<toml::Value>()?;
+        assert_eq!(expanded["dependencies"]["rand"].as_str(), Some("*"));
+        Ok(())
+    }
+
+    #[test]
+    fn convert() -> anyhow::Result<()> {
+        // Things that look like manifests in the body must be left as they are.
+        static BODY: &str = r#"
+fn main() {
+    let _ = "
+---
+// cargo-deps: a
+";
+}
+"#;
+
+        let scripts = [
+            (
+                ManifestFormat::DocComment,
+                "//! ```cargo\n//! [dependencies]\n//! regex = \"1\"\n//! ```\n",
+            ),
+            (
+                ManifestFormat::Frontmatter,
+                "---cargo\n[dependencies]\nregex = \"1\"\n---\n",
+            ),
+            (ManifestFormat::ShortDeps, "// cargo-deps: regex=\"1\"\n"),
+        ];
+
+        for &(from, from_header) in &scripts {
+            for &(to, to_header) in &scripts {
+                if from == to {
+                    continue;
+                }
+                for eol in &["\n", "\r\n"] {
+                    let from_script = format!("{}{}", from_header, BODY).replace('\n', eol);
+                    let to_script = format!("{}{}", to_header, BODY).replace('\n', eol);
+
+                    let (format, cargo_toml) = extract_manifest(&from_script)?.unwrap();
+                    assert_eq!(format, from);
+                    assert_eq!(
+                        embed_manifest(&from_script, &cargo_toml, Some(to))?,
+                        to_script,
+                        "{:?} → {:?} ({:?})",
+                        from,
+                        to,
+                        eol,
+                    );
+                }
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn convert_to_short_deps_refuses_other_keys() -> anyhow::Result<()> {
+        for script in &[
+            "---cargo\n[dependencies]\nregex = \"1\"\n\n[dev-dependencies]\nrand = \"0.7\"\n---\n",
+            "//! ```cargo\n//! [package]\n//! build = \"build.rs\"\n//! ```\n",
+        ] {
+            let (_, cargo_toml) = extract_manifest(script)?.unwrap();
+            let err =
+                embed_manifest(script, &cargo_toml, Some(ManifestFormat::ShortDeps)).unwrap_err();
+            assert!(
+                err.to_string()
+                    .ends_with("cannot be written in `// cargo-deps:`"),
+                "{}",
+                err,
+            );
+        }
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn token_file_permissions() -> anyhow::Result<()> {
+        use super::{warn_if_not_private, write_private};
+
+        use std::os::unix::fs::PermissionsExt as _;
+
+        let tempdir = tempfile::Builder::new()
+            .prefix("cargo-scripts-")
+            .tempdir()?;
+        let path = tempdir.path().join("github-token");
+        let mode =
+            || -> anyhow::Result<_> { Ok(fs::metadata(&path)?.permissions().mode() & 0o777) };
+        let token = CargoScriptsConfigToken::File {
+            path: path.to_str().unwrap().to_owned(),
+        };
+
+        assert_eq!(token.load(None)?, None);
+
+        write_private(&path, "token\n", false)?;
+        assert_eq!(mode()?, 0o600);
+        assert!(!warn_if_not_private(path.to_str().unwrap())?);
+        assert_eq!(token.load(None)?.as_deref(), Some("token\n"));
+
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o644))?;
+        assert!(warn_if_not_private(path.to_str().unwrap())?);
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o640))?;
+        assert!(warn_if_not_private(path.to_str().unwrap())?);
+
+        // An existing file is made private again.
+        write_private(&path, "token2\n", false)?;
+        assert_eq!(mode()?, 0o600);
+        assert_eq!(fs::read_to_string(&path)?, "token2\n");
+
+        // Nothing is written on a dry run.
+        write_private(tempdir.path().join("dry-run"), "token\n", true)?;
+        assert!(!tempdir.path().join("dry-run").exists());
+        Ok(())
+    }
+
+    #[test]
+    fn script_filenames_on_push() {
+        let gist = |filename: Option<&str>, package: Option<&str>| CargoScriptsConfigGist {
+            backend: Backend::Github,
+            id: "0123abcd".to_owned(),
+            revision: None,
+            filename: filename.map(ToOwned::to_owned),
+            package: package.map(ToOwned::to_owned),
+            split_modules: false,
+        };
+
+        for &(filename, package, expected) in &[
+            // Recorded before `filename` and `package` existed.
+            (None, None, ("solve.rs", "solve.rs")),
+            (Some("solve.rs"), None, ("solve.rs", "solve.rs")),
+            // Cloned, then pushed without renaming the package.
+            (Some("solve.rs"), Some("abc123_a"), ("solve.rs", "solve.rs")),
+            (
+                Some("abc123_a.rs"),
+                Some("abc123_a"),
+                ("abc123_a.rs", "abc123_a.rs"),
+            ),
+            // The package has been renamed.
+            (
+                Some("solve.rs"),
+                Some("abc123_old"),
+                ("solve.rs", "abc123_a.rs"),
+            ),
+            (None, Some("abc123_old"), ("solve.rs", "abc123_a.rs")),
+        ] {
+            let (remote_filename, script_filename) =
+                gist(filename, package).script_filenames("solve.rs", "abc123_a");
+            assert_eq!(
+                (&*remote_filename, &*script_filename),
+                expected,
+                "{:?}",
+                (filename, package),
+            );
+        }
+    }
+}
@@ -4,6 +4,10 @@ use human_panic::setup_panic;
 use structopt::StructOpt as _;
 
 fn main() -> anyhow::Result<()> {
-    setup_panic!();
+    // `setup_panic!` refers to `std::panic::PanicInfo`.
+    #[allow(deprecated)]
+    {
+        setup_panic!();
+    }
     cargo_scripts::run(Opt::from_args(), Context::new()?)
 }
@@ -0,0 +1,570 @@
+//! Moving modules between files and inline `mod` blocks.
+
+use anyhow::{anyhow, bail, Context as _};
+use proc_macro2::{TokenStream, TokenTree};
+use syn::ext::IdentExt as _;
+use syn::{Attribute, Item, ItemMod, Lit, Meta, MetaNameValue};
+
+use std::collections::BTreeSet;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
+/// Inlines the modules in other files into the crate root `src_path`, recursively.
+///
+/// The content of each file is indented one level deeper than the `mod` item. The lines inside
+/// multi-line literals are kept as they are. `#[path]` attributes are removed.
+///
+/// Also returns the paths of the inlined files.
+pub(crate) fn bundle(src_path: &Path) -> anyhow::Result<(String, Vec<PathBuf>)> {
+    let dir = src_path.parent().unwrap_or_else(|| "".as_ref());
+    let mut inlined = vec![];
+    let code = inline_file(src_path, dir, &mut vec![], &mut inlined)?;
+    Ok((code, inlined))
+}
+
+/// `dir` is the directory for the child modules of `path`.
+fn inline_file(
+    path: &Path,
+    dir: &Path,
+    stack: &mut Vec<PathBuf>,
+    inlined: &mut Vec<PathBuf>,
+) -> anyhow::Result<String> {
+    // `#[path]` can reach the same file in different ways, such as `../src/main.rs`.
+    let canonical = path
+        .canonicalize()
+        .with_context(|| format!("failed to read {}", path.display()))?;
+    if stack.contains(&canonical) {
+        bail!("{} includes itself", path.display());
+    }
+
+    let mut code = crate::read(path)?;
+    let file =
+        syn::parse_file(&code).with_context(|| format!("failed to parse {}", path.display()))?;
+    let path_base = path.parent().unwrap_or_else(|| "".as_ref());
+
+    stack.push(canonical);
+    let mut edits = vec![];
+    inline_items(
+        &code,
+        &file.items,
+        dir,
+        path_base,
+        stack,
+        inlined,
+        &mut edits,
+    )?;
+    stack.pop();
+
+    edits.sort_by_key(|(range, _)| range.start);
+    for (range, replacement) in edits.into_iter().rev() {
+        code.replace_range(range, &replacement);
+    }
+    Ok(code)
+}
+
+/// `path_base` is the directory that `#[path]` on `mod <name>;` is relative to, which differs from
+/// `dir` only at the top level of a non-`mod.rs` file. Inside inline modules, `#[path]` is relative
+/// to `dir` as rustc does.
+fn inline_items(
+    code: &str,
+    items: &[Item],
+    dir: &Path,
+    path_base: &Path,
+    stack: &mut Vec<PathBuf>,
+    inlined: &mut Vec<PathBuf>,
+    edits: &mut Vec<(Range<usize>, String)>,
+) -> anyhow::Result<()> {
+    for item in items {
+        let ItemMod {
+            attrs,
+            mod_token,
+            ident,
+            content,
+            semi,
+            ..
+        } = match item {
+            Item::Mod(item_mod) => item_mod,
+            _ => continue,
+        };
+        let name = ident.unraw().to_string();
+        let path_attr = path_attr(code, attrs);
+
+        match (content, semi) {
+            (Some((_, items)), _) => {
+                let dir = match &path_attr {
+                    Some((_, path)) => dir.join(path),
+                    None => dir.join(&name),
+                };
+                inline_items(code, items, &dir, &dir, stack, inlined, edits)?;
+            }
+            (None, Some(semi)) => {
+                let (path, child_dir) = match &path_attr {
+                    Some((_, path)) => {
+                        // Files loaded with `#[path]` own their directories as `mod.rs` does.
+                        let path = path_base.join(path);
+                        let child_dir = path.parent().unwrap_or(path_base).to_owned();
+                        (path, child_dir)
+                    }
+                    None => find_module_file(dir, &name)?,
+                };
+                let content = inline_file(&path, &child_dir, stack, inlined)?;
+                inlined.push(path);
+
+                if let Some((span, _)) = path_attr {
+                    edits.push((whole_lines(code, span), "".to_owned()));
+                }
+                let (start, end) = (mod_token.span.start(), semi.spans[0].end());
+                let start = crate::byte_offset(code, start.line, start.column);
+                let end = crate::byte_offset(code, end.line, end.column);
+                let eol = crate::line_ending(code);
+                let line_start = code[..start].rfind('\n').map_or(0, |i| i + 1);
+                let outer = &code[line_start..start];
+                let outer = &outer[..outer.len() - outer.trim_start().len()];
+                let replacement = if content.is_empty() {
+                    format!("mod {} {{}}", ident)
+                } else {
+                    let content = indent(&content, &format!("{}    ", outer), eol)?;
+                    format!("mod {} {{{}{}{}}}", ident, eol, content, outer)
+                };
+                edits.push((start..end, replacement));
+            }
+            (None, None) => unreachable!("a `mod` item has either a block or `;`"),
+        }
+    }
+    Ok(())
+}
+
+/// Moves the inline `mod` blocks of the crate root `code` into files, recursively.
+///
+/// Returns the new crate root and the files, whose paths are relative to the directory of the
+/// crate root. Modules with `#[path]` are left inline. The common indentation of each block is
+/// removed except from the lines that continue string literals.
+pub(crate) fn split(code: &str) -> anyhow::Result<(String, Vec<(PathBuf, String)>)> {
+    let mut files = vec![];
+    let code = split_file(code, "".as_ref(), &mut files)?;
+    files.sort();
+    Ok((code, files))
+}
+
+/// `dir` is the directory for the child modules of `code`.
+fn split_file(
+    code: &str,
+    dir: &Path,
+    files: &mut Vec<(PathBuf, String)>,
+) -> anyhow::Result<String> {
+    let file = syn::parse_file(code)?;
+    let eol = crate::line_ending(code);
+
+    let mut edits = vec![];
+    for item in &file.items {
+        let ItemMod {
+            attrs,
+            mod_token,
+            ident,
+            content,
+            ..
+        } = match item {
+            Item::Mod(item_mod) => item_mod,
+            _ => continue,
+        };
+        let brace = match content {
+            Some((brace, _)) if path_attr(code, attrs).is_none() => brace,
+            _ => continue,
+        };
+        let name = ident.unraw().to_string();
+
+        let (start, open, end) = (mod_token.span.start(), brace.span.start(), brace.span.end());
+        let start = crate::byte_offset(code, start.line, start.column);
+        let open = crate::byte_offset(code, open.line, open.column);
+        let end = crate::byte_offset(code, end.line, end.column);
+
+        let content = unindent(&code[open + 1..end - 1], eol)?;
+        let content = split_file(&content, &dir.join(&name), files)?;
+        files.push((dir.join(format!("{}.rs", name)), content));
+        edits.push((start..end, format!("mod {};", ident)));
+    }
+
+    let mut code = code.to_owned();
+    for (range, replacement) in edits.into_iter().rev() {
+        code.replace_range(range, &replacement);
+    }
+    Ok(code)
+}
+
+/// The content of a `{ .. }` block as a file, without the blank space around it and the common
+/// indentation.
+fn unindent(block: &str, eol: &str) -> anyhow::Result<String> {
+    let block = match block.find('\n') {
+        Some(i) if block[..i].trim().is_empty() => &block[i + 1..],
+        _ => block.trim_start(),
+    };
+    let block = block.trim_end_matches(&[' ', '\t'][..]);
+
+    let continuations = literal_continuation_lines(block)?;
+    let lines = block.split_inclusive('\n').collect::<Vec<_>>();
+    let indent = |line: &str| line.len() - line.trim_start_matches(&[' ', '\t'][..]).len();
+    let is_blank = |line: &str| line.trim().is_empty();
+
+    let common_indent = lines
+        .iter()
+        .enumerate()
+        .filter(|&(i, line)| !(continuations.contains(&i) || is_blank(line)))
+        .map(|(_, line)| indent(line))
+        .min()
+        .unwrap_or(0);
+
+    let mut file = lines
+        .iter()
+        .enumerate()
+        .map(|(i, &line)| {
+            if continuations.contains(&i) {
+                line
+            } else if is_blank(line) {
+                &line[line.trim_end_matches(&['\r', '\n'][..]).len()..]
+            } else {
+                &line[common_indent..]
+            }
+        })
+        .collect::<String>();
+    if !(file.is_empty() || file.ends_with('\n')) {
+        file += eol;
+    }
+    Ok(file)
+}
+
+/// The inverse of `unindent`.
+fn indent(file: &str, indent: &str, eol: &str) -> anyhow::Result<String> {
+    let continuations = literal_continuation_lines(file)?;
+    let mut block = file
+        .split_inclusive('\n')
+        .enumerate()
+        .map(|(i, line)| {
+            if continuations.contains(&i) || line.trim().is_empty() {
+                line.to_owned()
+            } else {
+                format!("{}{}", indent, line)
+            }
+        })
+        .collect::<String>();
+    if !(block.is_empty() || block.ends_with('\n')) {
+        block += eol;
+    }
+    Ok(block)
+}
+
+/// The 0-based indices of the lines that start inside a literal.
+///
+/// Block doc comments are not counted. Their indentation can be changed as that of the other
+/// comments.
+fn literal_continuation_lines(code: &str) -> anyhow::Result<BTreeSet<usize>> {
+    let mut lines = BTreeSet::new();
+    let mut streams = vec![code
+        .parse::<TokenStream>()
+        .map_err(|err| anyhow!("{:?}", err))?];
+    while let Some(stream) = streams.pop() {
+        for tt in stream {
+            match tt {
+                TokenTree::Group(group) => streams.push(group.stream()),
+                TokenTree::Literal(literal) => {
+                    // `line`s are 1-based.
+                    let (start, end) = (literal.span().start(), literal.span().end());
+                    if !code[crate::byte_offset(code, start.line, start.column)..].starts_with("/*")
+                    {
+                        lines.extend(start.line..end.line);
+                    }
+                }
+                TokenTree::Ident(_) | TokenTree::Punct(_) => {}
+            }
+        }
+    }
+    Ok(lines)
+}
+
+/// The span and the value of `#[path = ".."]`.
+fn path_attr(code: &str, attrs: &[Attribute]) -> Option<(Range<usize>, String)> {
+    attrs.iter().find_map(|attr| {
+        if !attr.path.is_ident("path") {
+            return None;
+        }
+        let path = match attr.parse_meta().ok()? {
+            Meta::NameValue(MetaNameValue {
+                lit: Lit::Str(lit), ..
+            }) => lit.value(),
+            _ => return None,
+        };
+        let (start, end) = (
+            attr.pound_token.spans[0].start(),
+            attr.bracket_token.span.end(),
+        );
+        let start = crate::byte_offset(code, start.line, start.column);
+        let end = crate::byte_offset(code, end.line, end.column);
+        Some((start..end, path))
+    })
+}
+
+/// `<dir>/<name>.rs` or `<dir>/<name>/mod.rs`, and the directory for the child modules.
+fn find_module_file(dir: &Path, name: &str) -> anyhow::Result<(PathBuf, PathBuf)> {
+    let file = dir.join(format!("{}.rs", name));
+    let mod_rs = dir.join(name).join("mod.rs");
+    match (file.exists(), mod_rs.exists()) {
+        (true, false) => Ok((file, dir.join(name))),
+        (false, true) => Ok((mod_rs, dir.join(name))),
+        (true, true) => bail!(
+            "both {} and {} exist for `mod {}`",
+            file.display(),
+            mod_rs.display(),
+            name,
+        ),
+        (false, false) => bail!(
+            "neither {} nor {} exists for `mod {}`",
+            file.display(),
+            mod_rs.display(),
+            name,
+        ),
+    }
+}
+
+/// Extends `span` to the whole lines, including the line terminator, if nothing else is in them.
+fn whole_lines(code: &str, span: Range<usize>) -> Range<usize> {
+    let line_start = code[..span.start].rfind('\n').map_or(0, |i| i + 1);
+    let rest = &code[span.end..];
+    let rest_of_line = rest.find('\n').map_or(rest, |i| &rest[..=i]);
+    if code[line_start..span.start].trim().is_empty() && rest_of_line.trim().is_empty() {
+        line_start..span.end + rest_of_line.len()
+    } else {
+        span
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::bundle;
+
+    use std::fs;
+    use std::path::Path;
+
+    #[test]
+    fn bundle_nested() -> anyhow::Result<()> {
+        let tempdir = tempfile::Builder::new()
+            .prefix("cargo-scripts-")
+            .tempdir()?;
+        let src = tempdir.path();
+        write(src, "main.rs", "mod a;\nmod b;\n\nfn main() {}\n")?;
+        write(src, "a.rs", "mod c;\n\npub fn a() {}\n")?;
+        write(src, "a/c.rs", "pub fn c() {}\n")?;
+        write(src, "b/mod.rs", "mod d;\n")?;
+        write(src, "b/d.rs", "pub const S: &str = \"\n  x\n\";\n")?;
+
+        assert_eq!(
+            bundle(&src.join("main.rs"))?.0,
+            r#"mod a {
+    mod c {
+        pub fn c() {}
+    }
+
+    pub fn a() {}
+}
+mod b {
+    mod d {
+        pub const S: &str = "
+  x
+";
+    }
+}
+
+fn main() {}
+"#,
+        );
+        assert_eq!(
+            bundle(&src.join("main.rs"))?.1,
+            ["a/c.rs", "a.rs", "b/d.rs", "b/mod.rs"]
+                .iter()
+                .map(|p| src.join(p))
+                .collect::<Vec<_>>(),
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn bundle_path() -> anyhow::Result<()> {
+        let tempdir = tempfile::Builder::new()
+            .prefix("cargo-scripts-")
+            .tempdir()?;
+        let src = tempdir.path();
+        write(
+            src,
+            "main.rs",
+            "#[path = \"x/y.rs\"]\nmod a;\n\nfn main() {}\n",
+        )?;
+        // A file loaded with `#[path]` owns its directory.
+        write(
+            src,
+            "x/y.rs",
+            "mod b;\n#[path = \"p\"]\nmod i {\n    mod c;\n}\n",
+        )?;
+        write(src, "x/b.rs", "")?;
+        // `src/x/y.rs` is a `mod.rs` file here, so `#[path]` in `mod i` is relative to `src/x`.
+        write(src, "x/p/c.rs", "")?;
+
+        assert_eq!(
+            bundle(&src.join("main.rs"))?.0,
+            "mod a {\n    mod b {}\n    #[path = \"p\"]\n    mod i {\n        mod c {}\n    }\n}\n\nfn main() {}\n",
+        );
+
+        // In a non-`mod.rs` file, `#[path]` in an inline module starts from the directory named
+        // after the file.
+        write(src, "main.rs", "mod a;\n\nfn main() {}\n")?;
+        write(src, "a.rs", "#[path = \"p\"]\nmod i {\n    mod c;\n}\n")?;
+        write(src, "a/p/c.rs", "fn c() {}\n")?;
+        assert_eq!(
+            bundle(&src.join("main.rs"))?.0,
+            "mod a {\n    #[path = \"p\"]\n    mod i {\n        mod c {\n            fn c() {}\n        }\n    }\n}\n\nfn main() {}\n",
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn bundle_self_include() -> anyhow::Result<()> {
+        let tempdir = tempfile::Builder::new()
+            .prefix("cargo-scripts-")
+            .tempdir()?;
+        let src = tempdir.path();
+        write(src, "main.rs", "mod a;\n\nfn main() {}\n")?;
+        write(src, "a.rs", "#[path = \"main.rs\"]\nmod m;\n")?;
+
+        let err = bundle(&src.join("main.rs")).unwrap_err();
+        assert!(err.to_string().ends_with("includes itself"), "{}", err);
+
+        let src = &src.join("src");
+        write(
+            src,
+            "main.rs",
+            "#[path = \"../src/main.rs\"]\nmod m;\n\nfn main() {}\n",
+        )?;
+        let err = bundle(&src.join("main.rs")).unwrap_err();
+        assert!(err.to_string().ends_with("includes itself"), "{}", err);
+        Ok(())
+    }
+
+    #[test]
+    fn split() -> anyhow::Result<()> {
+        let code = r##"mod a {
+    mod b {
+        pub fn f() {}
+    }
+
+    /** Block doc comment.
+      Indented in the comment.
+    */
+    pub const S: &str = r#"
+line "one"
+    line two
+"#;
+}
+
+#[path = "x.rs"]
+mod c {}
+
+fn main() {}
+"##;
+        let (main_rs, files) = super::split(code)?;
+        assert_eq!(
+            main_rs,
+            "mod a;\n\n#[path = \"x.rs\"]\nmod c {}\n\nfn main() {}\n"
+        );
+        assert_eq!(
+            files,
+            [
+                ("a/b.rs", "pub fn f() {}\n"),
+                (
+                    "a.rs",
+                    r##"mod b;
+
+/** Block doc comment.
+  Indented in the comment.
+*/
+pub const S: &str = r#"
+line "one"
+    line two
+"#;
+"##,
+                ),
+            ]
+            .iter()
+            .map(|&(p, c)| (p.into(), c.to_owned()))
+            .collect::<Vec<_>>(),
+        );
+
+        let tempdir = tempfile::Builder::new()
+            .prefix("cargo-scripts-")
+            .tempdir()?;
+        let src = tempdir.path();
+        write(src, "main.rs", &main_rs)?;
+        for (path, content) in &files {
+            write(src, path.to_str().unwrap(), content)?;
+        }
+        assert_eq!(bundle(&src.join("main.rs"))?.0, code);
+        Ok(())
+    }
+
+    #[test]
+    fn split_crlf() -> anyhow::Result<()> {
+        let (main_rs, files) =
+            super::split("mod a {\r\n    pub fn f() {}\r\n\r\n    fn g() {}\r\n}\r\n")?;
+        assert_eq!(main_rs, "mod a;\r\n");
+        assert_eq!(
+            files,
+            [(
+                "a.rs".into(),
+                "pub fn f() {}\r\n\r\nfn g() {}\r\n".to_owned()
+            )],
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn unindent() -> anyhow::Result<()> {
+        assert_eq!(super::unindent("", "\n")?, "");
+        assert_eq!(super::unindent(" fn f() {} ", "\n")?, "fn f() {}\n");
+        assert_eq!(
+            super::unindent("  \n    fn f() {}\n  \n      g!();\n  ", "\n")?,
+            "fn f() {}\n\n  g!();\n",
+        );
+        assert_eq!(
+            super::unindent("\r\n    fn f() {}\r\n\r\n    fn g() {}\r\n", "\r\n")?,
+            "fn f() {}\r\n\r\nfn g() {}\r\n",
+        );
+        assert_eq!(
+            super::unindent("\n    const S: &str = r\"\na\n    \";\n", "\n")?,
+            "const S: &str = r\"\na\n    \";\n",
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn literal_continuation_lines() -> anyhow::Result<()> {
+        let code = r##"const A: &str = r#"
+"#;
+/**
+ * doc
+ */
+const B: u8 = b'x';
+/* not a literal
+ */
+const C: &str = "a\
+    b";
+"##;
+        assert_eq!(
+            super::literal_continuation_lines(code)?,
+            [1, 9].iter().copied().collect(),
+        );
+        Ok(())
+    }
+
+    fn write(dir: &Path, path: &str, content: &str) -> anyhow::Result<()> {
+        let path = dir.join(path);
+        fs::create_dir_all(path.parent().unwrap())?;
+        fs::write(path, content)?;
+        Ok(())
+    }
+}
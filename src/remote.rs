@@ -0,0 +1,373 @@
+//! The services that scripts are synced with.
+
+mod git;
+mod github;
+mod gitlab;
+
+pub(crate) use self::git::Git;
+pub(crate) use self::github::Github;
+pub(crate) use self::gitlab::Gitlab;
+
+use crate::http;
+use anyhow::{anyhow, bail, Context as _};
+use indexmap::IndexMap;
+use itertools::Itertools as _;
+use url::Url;
+
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+/// A service or a repository that stores a script and its files as a flat set of files.
+///
+/// The write operations only log what they would do when `dry_run` is `true`.
+pub(crate) trait Remote: fmt::Debug + Sync {
+    /// The name of the service, such as `"GitHub"`.
+    fn name(&self) -> &'static str;
+
+    /// Checks that the service accepts `token`.
+    fn check_token(&self, token: &str) -> anyhow::Result<()>;
+
+    fn fetch(
+        &self,
+        token: Option<&str>,
+        id: &str,
+        revision: Option<&str>,
+    ) -> anyhow::Result<Snapshot>;
+
+    fn create(
+        &self,
+        token: &str,
+        files: &IndexMap<String, String>,
+        description: &str,
+        public: bool,
+        dry_run: bool,
+    ) -> anyhow::Result<Option<Pushed>>;
+
+    /// Overwrites `files` and deletes `removed`. The other files are left as they are.
+    ///
+    /// With `rename: Some((from, to))`, `from` is renamed to `to`. `files` contains `to`.
+    #[allow(clippy::too_many_arguments)]
+    fn update(
+        &self,
+        token: &str,
+        id: &str,
+        files: &IndexMap<String, String>,
+        removed: &[String],
+        rename: Option<(&str, &str)>,
+        description: Option<&str>,
+        dry_run: bool,
+    ) -> anyhow::Result<Option<Pushed>>;
+
+    fn delete(&self, token: &str, id: &str, dry_run: bool) -> anyhow::Result<()>;
+
+    /// Lists the scripts owned by the user of `token`.
+    fn list(&self, token: &str) -> anyhow::Result<Vec<Listing>>;
+
+    /// `None` if the script has no web page.
+    fn html_url(&self, id: &str) -> anyhow::Result<Option<Url>>;
+}
+
+#[derive(Debug)]
+pub(crate) struct Snapshot {
+    /// `filename` → `content`
+    pub(crate) files: IndexMap<String, String>,
+    /// `None` if the service does not keep revisions.
+    pub(crate) revision: Option<String>,
+    /// Newest first.
+    pub(crate) history: Vec<Revision>,
+}
+
+#[derive(Debug)]
+pub(crate) struct Revision {
+    pub(crate) version: String,
+    pub(crate) committed_at: String,
+    pub(crate) additions: u64,
+    pub(crate) deletions: u64,
+}
+
+#[derive(Debug)]
+pub(crate) struct Pushed {
+    pub(crate) id: String,
+    pub(crate) revision: Option<String>,
+}
+
+#[derive(Debug)]
+pub(crate) struct Listing {
+    pub(crate) id: String,
+    pub(crate) description: Option<String>,
+    pub(crate) public: bool,
+    /// `filename` → `content`, if the list includes it
+    pub(crate) files: Vec<(String, Option<String>)>,
+}
+
+#[derive(Debug)]
+pub(crate) struct RemoteScript {
+    pub(crate) script: ScriptFile,
+    pub(crate) files: Vec<(PathBuf, String)>,
+    pub(crate) revision: Option<String>,
+}
+
+#[derive(Debug)]
+pub(crate) struct ScriptFile {
+    pub(crate) filename: String,
+    pub(crate) content: String,
+}
+
+impl RemoteScript {
+    pub(crate) fn fetch(
+        remote: &dyn Remote,
+        token: Option<&str>,
+        id: &str,
+        revision: Option<&str>,
+    ) -> anyhow::Result<Self> {
+        Self::new(remote.fetch(token, id, revision)?)
+    }
+
+    /// Picks the script out of the files.
+    ///
+    /// The script is the only top-level Rust file, or the only one with an embedded manifest.
+    fn new(snapshot: Snapshot) -> anyhow::Result<Self> {
+        let Snapshot {
+            mut files,
+            revision,
+            ..
+        } = snapshot;
+
+        let top_level_rust_files = files
+            .iter()
+            .filter(|(filename, _)| is_rust(filename) && !filename.contains(PATH_SEP))
+            .collect::<Vec<_>>();
+
+        let script_filename = if top_level_rust_files.len() == 1 {
+            top_level_rust_files[0].0
+        } else {
+            top_level_rust_files
+                .iter()
+                .filter(|(_, content)| crate::ManifestFormat::detect(content).is_some())
+                .exactly_one()
+                .map_err(|err| {
+                    let mut err = err.peekable();
+                    if err.peek().is_some() {
+                        anyhow!(
+                            "multiple Rust files: [{}]",
+                            err.format_with(", ", |(filename, _), f| f(&filename)),
+                        )
+                    } else {
+                        anyhow!("no Rust files found")
+                    }
+                })?
+                .0
+        }
+        .clone();
+
+        let content = files.shift_remove(&script_filename).unwrap();
+        let files = files
+            .into_iter()
+            .map(|(filename, content)| Ok((package_path(&filename)?, content)))
+            .collect::<anyhow::Result<_>>()?;
+
+        Ok(Self {
+            script: ScriptFile {
+                filename: script_filename,
+                content,
+            },
+            files,
+            revision,
+        })
+    }
+
+    pub(crate) fn has_same_content(&self, script: &str, files: &[(PathBuf, String)]) -> bool {
+        // The paths are unique on both sides.
+        self.script.content == script
+            && self.files.len() == files.len()
+            && files.iter().all(|(path, content)| {
+                self.files.iter().any(|(remote_path, remote_content)| {
+                    remote_path == path && remote_content == content
+                })
+            })
+    }
+
+    /// The remote filenames of the files that are not in `local_files`.
+    pub(crate) fn removed_files(
+        &self,
+        local_files: &[(PathBuf, String)],
+    ) -> anyhow::Result<Vec<String>> {
+        self.files
+            .iter()
+            .filter(|(path, _)| local_files.iter().all(|(local, _)| local != path))
+            .map(|(path, _)| remote_filename(path))
+            .collect()
+    }
+
+    /// Splits the script into `src_path` and `manifest_path`, and resolves the other files.
+    pub(crate) fn unpack(
+        self,
+        workspace_root: &Path,
+        package_name: &str,
+        src_path: &Path,
+        manifest_path: &Path,
+        package_dir: &Path,
+        split_modules: bool,
+    ) -> anyhow::Result<Vec<(PathBuf, String)>> {
+        let (code, cargo_toml) = crate::split_script(&self.script.content, workspace_root, || {
+            Ok(package_name.to_owned())
+        })?;
+        let (code, modules) = if split_modules {
+            crate::modules::split(&code)?
+        } else {
+            (code, vec![])
+        };
+        let src_dir = src_path.parent().unwrap_or_else(|| "".as_ref());
+        let lib_path = src_dir.join("lib.rs");
+        let files = self
+            .files
+            .into_iter()
+            .map(|(path, content)| (package_dir.join(path), content))
+            .collect::<Vec<_>>();
+        for (path, _) in &files {
+            if [manifest_path, src_path, &lib_path].contains(&&**path) {
+                bail!("a file in the remote would overwrite {}", path.display());
+            }
+        }
+        let modules = modules
+            .into_iter()
+            .map(|(path, content)| (src_dir.join(path), content))
+            .collect::<Vec<_>>();
+        for (path, _) in &modules {
+            if [src_path, &lib_path].contains(&&**path) || files.iter().any(|(p, _)| p == path) {
+                bail!(
+                    "cannot split the modules: {} would overwrite another file",
+                    path.display(),
+                );
+            }
+        }
+        Ok(vec![
+            (src_path.to_owned(), code),
+            (manifest_path.to_owned(), cargo_toml),
+        ]
+        .into_iter()
+        .chain(modules)
+        .chain(files)
+        .collect())
+    }
+}
+
+pub(crate) fn is_rust(filename: &str) -> bool {
+    [Some("rs".as_ref()), Some("crs".as_ref())].contains(&Path::new(filename).extension())
+}
+
+/// The script does not exist in a remote that is not an HTTP API.
+#[derive(Debug)]
+pub(crate) struct NotFound(pub(crate) String);
+
+impl fmt::Display for NotFound {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "`{}` does not exist", self.0)
+    }
+}
+
+impl std::error::Error for NotFound {}
+
+pub(crate) fn is_not_found(err: &anyhow::Error) -> bool {
+    err.is::<NotFound>()
+        || err
+            .downcast_ref::<http::ApiError>()
+            .is_some_and(|err| err.status() == 404)
+}
+
+// Gists and snippets are flat. `/` in package paths is escaped as `%2F` (and `%` as `%25`).
+static PATH_SEP: &str = "%2F";
+
+pub(crate) fn remote_filename(path: &Path) -> anyhow::Result<String> {
+    path.components()
+        .map(|component| match component {
+            std::path::Component::Normal(s) => s
+                .to_str()
+                .map(|s| s.replace('%', "%25"))
+                .with_context(|| format!("{:?} is not valid UTF-8 path", path)),
+            _ => Err(anyhow!("invalid path: {:?}", path)),
+        })
+        .collect::<anyhow::Result<Vec<_>>>()
+        .map(|components| components.join(PATH_SEP))
+}
+
+fn package_path(remote_filename: &str) -> anyhow::Result<PathBuf> {
+    remote_filename
+        .split(PATH_SEP)
+        .map(|component| {
+            let component = component.replace("%25", "%");
+            if ["", ".", ".."].contains(&&*component) || component.contains(['/', '\\']) {
+                bail!("invalid remote filename: {:?}", remote_filename);
+            }
+            Ok(component)
+        })
+        .collect()
+}
+
+/// Appends `/` to the path of `base` so that `Url::join` keeps the last segment.
+fn with_trailing_slash(mut base: Url) -> Url {
+    if !base.path().ends_with('/') {
+        base.set_path(&format!("{}/", base.path()));
+    }
+    base
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{package_path, remote_filename};
+
+    use std::path::Path;
+
+    #[test]
+    fn remote_filename_round_trip() -> anyhow::Result<()> {
+        for (path, expected) in &[
+            ("a.rs", "a.rs"),
+            ("src/a.rs", "src%2Fa.rs"),
+            ("src/a/b.rs", "src%2Fa%2Fb.rs"),
+            ("a%2Fb.rs", "a%252Fb.rs"),
+            ("a%25b.rs", "a%2525b.rs"),
+            ("src/%/%2F", "src%2F%25%2F%252F"),
+            ("a%/b", "a%25%2Fb"),
+        ] {
+            assert_eq!(remote_filename(Path::new(path))?, *expected);
+            assert_eq!(package_path(expected)?, Path::new(path));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn remote_filename_rejects_non_normal_components() {
+        assert!(remote_filename(Path::new("../a.rs")).is_err());
+        assert!(remote_filename(Path::new("/a.rs")).is_err());
+    }
+
+    #[test]
+    fn package_path_rejects_escapes() {
+        for remote_filename in &[
+            "",
+            "..",
+            ".",
+            "..%2Fa.rs",
+            "a%2F..%2F..%2Fb.rs",
+            "%2Fa.rs",
+            "a%2F%2Fb.rs",
+            "a%2F",
+            "..%252Fa.rs%2F..",
+            "a\\b.rs",
+            "..\\a.rs",
+            "a/b.rs",
+        ] {
+            assert!(
+                package_path(remote_filename).is_err(),
+                "{:?}",
+                remote_filename
+            );
+        }
+        // Only `%2F` and `%25` are escapes.
+        assert_eq!(
+            package_path("%2E%2E%2Fa.rs").unwrap(),
+            Path::new("%2E%2E/a.rs")
+        );
+        assert_eq!(package_path("..%252Fa.rs").unwrap(), Path::new("..%2Fa.rs"));
+    }
+}
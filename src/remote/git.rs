@@ -0,0 +1,468 @@
+//! A plain git repository with one script per file.
+//!
+//! The ID of a script is its path in the repository. Only the script itself is stored, so the
+//! other files of a package cannot be pushed. The repository is cloned into a temporary directory
+//! once per `Git`, which lives as long as a command.
+
+use super::{Listing, NotFound, Pushed, Remote, Revision, Snapshot};
+use anyhow::{bail, Context as _};
+use indexmap::IndexMap;
+use itertools::Itertools as _;
+use log::info;
+use tempfile::TempDir;
+use url::Url;
+
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+use std::sync::Mutex;
+
+#[derive(Debug)]
+pub(crate) struct Git {
+    url: String,
+    branch: String,
+    /// Directory in the repository to create new scripts in.
+    dir: String,
+    /// Relative local paths in `url` are resolved from here.
+    cwd: PathBuf,
+    worktree: Mutex<Option<Worktree>>,
+}
+
+impl Git {
+    pub(crate) fn new(url: String, branch: String, dir: String, cwd: PathBuf) -> Self {
+        Self {
+            url,
+            branch,
+            dir,
+            cwd,
+            worktree: Mutex::new(None),
+        }
+    }
+
+    /// Runs `f` on the clone, cloning the repository first if this is the first time.
+    ///
+    /// The clone is discarded when `f` fails since it may have been left with unpushed changes.
+    fn with_worktree<T>(
+        &self,
+        f: impl FnOnce(&mut Worktree) -> anyhow::Result<T>,
+    ) -> anyhow::Result<T> {
+        let mut worktree = self.worktree.lock().unwrap();
+        if worktree.is_none() {
+            *worktree = Some(self.clone()?);
+        }
+        let ret = f(worktree.as_mut().expect("should have been set"));
+        if ret.is_err() {
+            *worktree = None;
+        }
+        ret
+    }
+
+    /// Clones the repository and checks out `branch`, or an empty `branch` if it does not exist
+    /// yet.
+    fn clone(&self) -> anyhow::Result<Worktree> {
+        let dir = tempfile::Builder::new()
+            .prefix("cargo-scripts-")
+            .tempdir()
+            .with_context(|| "failed to create a temporary directory")?;
+
+        let args = [
+            "clone",
+            "--quiet",
+            "--no-checkout",
+            "--",
+            &self.url,
+            &dir.path().to_string_lossy(),
+        ];
+        crate::info_cmd("git", &args);
+        duct::cmd("git", &args).dir(&self.cwd).run()?;
+
+        let worktree = Worktree { dir, exists: false };
+        let remote_branch = format!("refs/remotes/origin/{}", self.branch);
+        let exists = worktree
+            .cmd(&["rev-parse", "--verify", "--quiet", &remote_branch])
+            .stdout_null()
+            .unchecked()
+            .run()?
+            .status
+            .success();
+        if exists {
+            worktree.read(&["checkout", "--quiet", "-B", &self.branch, &remote_branch])?;
+        } else {
+            worktree.read(&["checkout", "--quiet", "--orphan", &self.branch])?;
+        }
+        Ok(Worktree { exists, ..worktree })
+    }
+
+    /// Writes `content` to `path` (or removes `path` if `content` is `None`), then commits and
+    /// pushes it.
+    fn commit(
+        &self,
+        worktree: &mut Worktree,
+        path: &str,
+        content: Option<&str>,
+        message: &str,
+        dry_run: bool,
+    ) -> anyhow::Result<Option<Pushed>> {
+        if dry_run {
+            info!(
+                "[dry-run] Commit {} and push it to {} ({})",
+                path, self.url, self.branch,
+            );
+            return Ok(None);
+        }
+
+        match content {
+            Some(content) => {
+                let abs_path = worktree.dir.path().join(path);
+                if let Some(parent) = abs_path.parent() {
+                    fs::create_dir_all(parent)
+                        .with_context(|| format!("failed to create {}", parent.display()))?;
+                }
+                fs::write(&abs_path, content)
+                    .with_context(|| format!("failed to write {}", abs_path.display()))?;
+                worktree.read(&["add", "--", path])?;
+            }
+            None => {
+                worktree.read(&["rm", "--quiet", "--", path])?;
+            }
+        }
+        worktree.run(&["commit", "--quiet", "-m", message])?;
+        worktree.run(&[
+            "push",
+            "--quiet",
+            "origin",
+            &format!("HEAD:refs/heads/{}", self.branch),
+        ])?;
+        worktree.exists = true;
+
+        Ok(Some(Pushed {
+            id: path.to_owned(),
+            revision: Some(worktree.read(&["rev-parse", "HEAD"])?),
+        }))
+    }
+}
+
+impl Remote for Git {
+    fn name(&self) -> &'static str {
+        "git"
+    }
+
+    /// `git` uses its own credentials.
+    fn check_token(&self, _: &str) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    fn fetch(
+        &self,
+        _: Option<&str>,
+        path: &str,
+        revision: Option<&str>,
+    ) -> anyhow::Result<Snapshot> {
+        check_path(path)?;
+        let rev = revision.unwrap_or("HEAD");
+
+        self.with_worktree(|worktree| {
+            if !worktree.exists || !worktree.has_file(rev, path)? {
+                return Err(NotFound(path.to_owned()).into());
+            }
+
+            let content = worktree.read_raw(&["show", &format!("{}:{}", rev, path)])?;
+            let history = worktree.history(rev, path)?;
+
+            Ok(Snapshot {
+                files: vec![(filename(path).to_owned(), content)]
+                    .into_iter()
+                    .collect(),
+                revision: history.first().map(|r| r.version.clone()),
+                history,
+            })
+        })
+    }
+
+    fn create(
+        &self,
+        _: &str,
+        files: &IndexMap<String, String>,
+        description: &str,
+        _: bool,
+        dry_run: bool,
+    ) -> anyhow::Result<Option<Pushed>> {
+        let (filename, content) = only_script(files)?;
+        let path = Path::new(&self.dir).join(filename);
+        let path = path
+            .to_str()
+            .with_context(|| format!("{:?} is not valid UTF-8", path))?;
+        check_path(path)?;
+
+        let message = if description.is_empty() {
+            format!("Add {}", path)
+        } else {
+            description.to_owned()
+        };
+        self.with_worktree(|worktree| {
+            if worktree.exists && worktree.has_file("HEAD", path)? {
+                bail!("{} already exists in {} ({})", path, self.url, self.branch);
+            }
+            self.commit(worktree, path, Some(content), &message, dry_run)
+        })
+    }
+
+    /// The script stays at `path`. `removed` is empty since only the script is stored. `rename` is
+    /// ignored.
+    fn update(
+        &self,
+        _: &str,
+        path: &str,
+        files: &IndexMap<String, String>,
+        _: &[String],
+        _: Option<(&str, &str)>,
+        description: Option<&str>,
+        dry_run: bool,
+    ) -> anyhow::Result<Option<Pushed>> {
+        let (_, content) = only_script(files)?;
+        check_path(path)?;
+
+        let message = description
+            .map(ToOwned::to_owned)
+            .unwrap_or_else(|| format!("Update {}", path));
+        self.with_worktree(|worktree| self.commit(worktree, path, Some(content), &message, dry_run))
+    }
+
+    fn delete(&self, _: &str, path: &str, dry_run: bool) -> anyhow::Result<()> {
+        check_path(path)?;
+
+        self.with_worktree(|worktree| {
+            if !worktree.exists || !worktree.has_file("HEAD", path)? {
+                return Err(NotFound(path.to_owned()).into());
+            }
+            self.commit(worktree, path, None, &format!("Remove {}", path), dry_run)?;
+            Ok(())
+        })
+    }
+
+    fn list(&self, _: &str) -> anyhow::Result<Vec<Listing>> {
+        self.with_worktree(|worktree| {
+            if !worktree.exists {
+                return Ok(vec![]);
+            }
+            worktree
+                .read(&["ls-tree", "-r", "--name-only", "HEAD"])?
+                .lines()
+                .map(|path| {
+                    let content = worktree.read_raw(&["show", &format!("HEAD:{}", path)]).ok();
+                    Ok(Listing {
+                        id: path.to_owned(),
+                        description: None,
+                        public: true,
+                        files: vec![(filename(path).to_owned(), content)],
+                    })
+                })
+                .collect()
+        })
+    }
+
+    fn html_url(&self, _: &str) -> anyhow::Result<Option<Url>> {
+        Ok(None)
+    }
+}
+
+#[derive(Debug)]
+struct Worktree {
+    dir: TempDir,
+    /// Whether the branch exists in the remote.
+    exists: bool,
+}
+
+impl Worktree {
+    fn cmd(&self, args: &[&str]) -> duct::Expression {
+        duct::cmd("git", args).dir(self.dir.path())
+    }
+
+    /// Runs `git` with logging.
+    fn run(&self, args: &[&str]) -> anyhow::Result<()> {
+        crate::info_cmd("git", args);
+        self.cmd(args).run()?;
+        Ok(())
+    }
+
+    /// Runs `git` and returns the trimmed stdout.
+    fn read(&self, args: &[&str]) -> anyhow::Result<String> {
+        self.cmd(args).read().map_err(Into::into)
+    }
+
+    /// Runs `git` and returns the stdout as it is.
+    fn read_raw(&self, args: &[&str]) -> anyhow::Result<String> {
+        let stdout = self.cmd(args).stdout_capture().run()?.stdout;
+        String::from_utf8(stdout).with_context(|| "the file is not valid UTF-8")
+    }
+
+    fn has_file(&self, rev: &str, path: &str) -> anyhow::Result<bool> {
+        let status = self
+            .cmd(&["cat-file", "-e", &format!("{}:{}", rev, path)])
+            .stderr_null()
+            .unchecked()
+            .run()?
+            .status;
+        Ok(status.success())
+    }
+
+    /// The commits that changed `path`, newest first.
+    fn history(&self, rev: &str, path: &str) -> anyhow::Result<Vec<Revision>> {
+        let log = self.read(&["log", "--format=%H %cI", "--numstat", rev, "--", path])?;
+
+        let mut history = vec![];
+        for line in log.lines().filter(|l| !l.is_empty()) {
+            match line.split('\t').collect::<Vec<_>>()[..] {
+                [additions, deletions, _] => {
+                    if let Some(Revision {
+                        additions: a,
+                        deletions: d,
+                        ..
+                    }) = history.last_mut()
+                    {
+                        // `-` for binary files.
+                        *a += additions.parse().unwrap_or(0);
+                        *d += deletions.parse().unwrap_or(0);
+                    }
+                }
+                _ => {
+                    let (version, committed_at) = line
+                        .splitn(2, ' ')
+                        .collect_tuple()
+                        .with_context(|| format!("unexpected output of `git log`: {:?}", line))?;
+                    history.push(Revision {
+                        version: version.to_owned(),
+                        committed_at: committed_at.to_owned(),
+                        additions: 0,
+                        deletions: 0,
+                    });
+                }
+            }
+        }
+        Ok(history)
+    }
+}
+
+fn only_script(files: &IndexMap<String, String>) -> anyhow::Result<(&String, &String)> {
+    if files.len() > 1 {
+        bail!(
+            "a git remote stores only the script. cannot push the other files: [{}]",
+            files.keys().skip(1).format(", "),
+        );
+    }
+    files.iter().next().with_context(|| "no files to push")
+}
+
+fn check_path(path: &str) -> anyhow::Result<()> {
+    let is_valid = !path.is_empty()
+        && Path::new(path)
+            .components()
+            .all(|c| matches!(c, Component::Normal(_)));
+    if !is_valid {
+        bail!("invalid path in the repository: {:?}", path);
+    }
+    Ok(())
+}
+
+fn filename(path: &str) -> &str {
+    path.rsplit('/').next().unwrap_or(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Git;
+    use crate::remote::{is_not_found, Remote as _};
+
+    use indexmap::indexmap;
+
+    #[test]
+    fn clone_push_pull() -> anyhow::Result<()> {
+        let tempdir = tempfile::Builder::new()
+            .prefix("cargo-scripts-")
+            .tempdir()?;
+        let bare = tempdir.path().join("scripts.git");
+        duct::cmd!("git", "init", "--quiet", "--bare", &bare).run()?;
+        // The identity is set in each clone so that the environment of the other tests is left
+        // as it is.
+        let new_git = || -> anyhow::Result<_> {
+            let git = Git::new(
+                "scripts.git".to_owned(),
+                "main".to_owned(),
+                "scripts".to_owned(),
+                tempdir.path().to_owned(),
+            );
+            git.with_worktree(|worktree| {
+                worktree.read(&["config", "user.name", "cargo-scripts"])?;
+                worktree.read(&["config", "user.email", "cargo-scripts@example.com"])?;
+                Ok(())
+            })?;
+            Ok(git)
+        };
+
+        let git = new_git()?;
+        assert!(git.list("")?.is_empty());
+        assert!(is_not_found(
+            &git.fetch(None, "scripts/a.rs", None).unwrap_err()
+        ));
+
+        // The failed fetch has discarded the clone along with the identity.
+        let git = new_git()?;
+        let v1 = "fn main() {}\n";
+        let pushed = git
+            .create(
+                "",
+                &indexmap!("a.rs".to_owned() => v1.to_owned()),
+                "",
+                true,
+                false,
+            )?
+            .unwrap();
+        assert_eq!(pushed.id, "scripts/a.rs");
+        let rev1 = pushed.revision.unwrap();
+
+        // Another command sees the push.
+        let git = new_git()?;
+        let snapshot = git.fetch(None, "scripts/a.rs", None)?;
+        assert_eq!(snapshot.files["a.rs"], v1);
+        assert_eq!(snapshot.revision.as_ref(), Some(&rev1));
+
+        let v2 = "fn main() {\n    println!();\n}\n";
+        let rev2 = git
+            .update(
+                "",
+                "scripts/a.rs",
+                &indexmap!("a.rs".to_owned() => v2.to_owned()),
+                &[],
+                None,
+                None,
+                false,
+            )?
+            .unwrap()
+            .revision
+            .unwrap();
+        assert_ne!(rev1, rev2);
+
+        let git = new_git()?;
+        let snapshot = git.fetch(None, "scripts/a.rs", None)?;
+        assert_eq!(snapshot.files["a.rs"], v2);
+        assert_eq!(snapshot.revision.as_ref(), Some(&rev2));
+        assert_eq!(
+            snapshot
+                .history
+                .iter()
+                .map(|r| &*r.version)
+                .collect::<Vec<_>>(),
+            [&*rev2, &*rev1],
+        );
+        assert_eq!(
+            git.fetch(None, "scripts/a.rs", Some(&rev1))?.files["a.rs"],
+            v1
+        );
+        assert_eq!(git.list("")?[0].id, "scripts/a.rs");
+
+        git.delete("", "scripts/a.rs", false)?;
+        assert!(is_not_found(
+            &new_git()?.fetch(None, "scripts/a.rs", None).unwrap_err()
+        ));
+        Ok(())
+    }
+}
@@ -0,0 +1,678 @@
+//! GitHub Gist.
+//!
+//! Fetched gists are cached in `<cache dir>/cargo-scripts/gists/<API host>/<id>.json` along with
+//! their `ETag`s, so that unchanged gists are not downloaded again.
+
+use super::{Listing, Pushed, Remote, Revision, Snapshot};
+use crate::http;
+use anyhow::{bail, ensure};
+use indexmap::IndexMap;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use url::Url;
+
+use std::path::{Path, PathBuf};
+
+// https://docs.github.com/en/rest/gists/gists#truncation
+const GIST_MAX_FILES: usize = 300;
+const GIST_API_FILE_SIZE_LIMIT: u64 = 1024 * 1024;
+const GIST_RAW_FILE_SIZE_LIMIT: u64 = 10 * 1024 * 1024;
+
+#[derive(Debug)]
+pub(crate) struct Github {
+    base: Url,
+    http: http::Client,
+    /// `<cache_dir>/<host>` where `<host>` is the host (and the port) of `base`. `None` if there is
+    /// no cache directory.
+    cache_dir: Option<PathBuf>,
+    /// Serves `fetch` from the cache without sending any request.
+    offline: bool,
+}
+
+impl Github {
+    pub(crate) fn new(
+        base: Url,
+        http: http::Client,
+        cache_dir: Option<PathBuf>,
+        offline: bool,
+    ) -> Self {
+        let base = super::with_trailing_slash(base);
+        // Gist IDs are unique only within one server.
+        let cache_dir = cache_dir.and_then(|cache_dir| {
+            let host = base.host_str()?;
+            Some(cache_dir.join(match base.port() {
+                Some(port) => format!("{}_{}", host, port),
+                None => host.to_owned(),
+            }))
+        });
+        Self {
+            base,
+            http,
+            cache_dir,
+            offline,
+        }
+    }
+
+    fn gists_url(&self) -> anyhow::Result<Url> {
+        self.base.join("gists").map_err(Into::into)
+    }
+
+    fn gist_url(&self, gist_id: &str) -> anyhow::Result<Url> {
+        self.base
+            .join(&format!("gists/{}", gist_id))
+            .map_err(Into::into)
+    }
+
+    fn user_url(&self) -> anyhow::Result<Url> {
+        self.base.join("user").map_err(Into::into)
+    }
+
+    fn gist_revision_url(&self, gist_id: &str, revision: &str) -> anyhow::Result<Url> {
+        self.base
+            .join(&format!("gists/{}/{}", gist_id, revision))
+            .map_err(Into::into)
+    }
+
+    /// `<cache dir>/<id>.json` or `<cache dir>/<id>@<revision>.json`.
+    ///
+    /// `None` for IDs and revisions that are not plain hexadecimal-like strings.
+    fn cache_path(&self, gist_id: &str, revision: Option<&str>) -> Option<PathBuf> {
+        let is_plain = |s: &str| !s.is_empty() && s.chars().all(|c| c.is_ascii_alphanumeric());
+        let cache_dir = self.cache_dir.as_ref()?;
+        let filename = match revision {
+            None if is_plain(gist_id) => format!("{}.json", gist_id),
+            Some(revision) if is_plain(gist_id) && is_plain(revision) => {
+                format!("{}@{}.json", gist_id, revision)
+            }
+            _ => return None,
+        };
+        Some(cache_dir.join(filename))
+    }
+
+    /// Retrieves the raw contents of the truncated files.
+    fn fill_truncated(&self, gist_id: &str, gist: &mut Gist) -> anyhow::Result<()> {
+        if gist.truncated {
+            bail!(
+                "`{}` has more than {} files and the list is truncated",
+                gist_id,
+                GIST_MAX_FILES,
+            );
+        }
+
+        for file in gist.files.values_mut().filter(|f| f.truncated) {
+            if file.size > GIST_RAW_FILE_SIZE_LIMIT {
+                bail!(
+                    "{} is larger than {} bytes, which cannot be retrieved via the API",
+                    file.filename,
+                    GIST_RAW_FILE_SIZE_LIMIT,
+                );
+            }
+
+            info!("{} is truncated. Retrieving the raw content", file.filename);
+            info!("GET: {}", file.raw_url);
+            let res = self
+                .http
+                .send_expecting("GET", &file.raw_url, None, None, 200)?;
+            file.content = res.into_string()?;
+            file.truncated = false;
+        }
+        Ok(())
+    }
+
+    /// Sends `payload` to `url` and returns the ID and the latest revision of the gist.
+    fn send_files(
+        &self,
+        method: &str,
+        url: &Url,
+        token: &str,
+        payload: &serde_json::Value,
+        expected: u16,
+    ) -> anyhow::Result<Pushed> {
+        info!("{} {}", method, url);
+        let res = self
+            .http
+            .send_expecting(method, url, Some(token), Some(payload), expected)?;
+        let Gist { id, history } = serde_json::from_str(&res.into_string()?)?;
+        return Ok(Pushed {
+            id,
+            revision: history.into_iter().next().map(|h| h.version),
+        });
+
+        #[derive(Deserialize)]
+        struct Gist {
+            id: String,
+            #[serde(default)]
+            history: Vec<GistHistoryEntry>,
+        }
+    }
+}
+
+impl Remote for Github {
+    fn name(&self) -> &'static str {
+        "GitHub"
+    }
+
+    /// Checks that `GET /user` accepts `token` and that the token has the `gist` scope.
+    fn check_token(&self, token: &str) -> anyhow::Result<()> {
+        let url = self.user_url()?;
+
+        info!("GET: {}", url);
+        let res = self
+            .http
+            .send_expecting("GET", &url, Some(token), None, 200)?;
+
+        // Fine-grained tokens do not have OAuth scopes.
+        if let Some(scopes) = res.header("X-OAuth-Scopes") {
+            ensure!(
+                scopes.split(',').any(|scope| scope.trim() == "gist"),
+                "the GitHub token does not have the `gist` scope (scopes: {:?})",
+                scopes,
+            );
+        }
+        Ok(())
+    }
+
+    fn fetch(
+        &self,
+        token: Option<&str>,
+        gist_id: &str,
+        revision: Option<&str>,
+    ) -> anyhow::Result<Snapshot> {
+        let url = match revision {
+            None => self.gist_url(gist_id)?,
+            Some(revision) => self.gist_revision_url(gist_id, revision)?,
+        };
+        let cache_path = self.cache_path(gist_id, revision);
+        let cached = cache_path.as_deref().and_then(read_cache);
+
+        let Gist { files, history, .. } = if self.offline {
+            match cached {
+                Some(CachedGist { gist, .. }) => {
+                    info!("Using the cached `{}` (offline)", gist_id);
+                    gist
+                }
+                None => bail!("`{}` is not cached. run without `--offline`", gist_id),
+            }
+        } else {
+            let etag = cached.as_ref().and_then(|c| c.etag.as_deref());
+
+            info!("GET: {}", url);
+            match self.http.get_if_none_match(&url, token, etag)? {
+                None => {
+                    info!("Using the cached `{}`", gist_id);
+                    cached.expect("should be `Some` if `etag` is").gist
+                }
+                Some(res) => {
+                    let etag = res.header("ETag").map(ToOwned::to_owned);
+                    let mut gist = serde_json::from_str(&res.into_string()?)?;
+                    self.fill_truncated(gist_id, &mut gist)?;
+                    let cached = CachedGist { etag, gist };
+                    if let Some(cache_path) = &cache_path {
+                        // Failing to write the cache is not an error.
+                        if let Err(err) = write_cache(cache_path, &cached) {
+                            warn!("Failed to cache `{}`: {}", gist_id, err);
+                        }
+                    }
+                    cached.gist
+                }
+            }
+        };
+
+        Ok(Snapshot {
+            files: files
+                .into_iter()
+                .map(
+                    |(
+                        _,
+                        GistFile {
+                            filename, content, ..
+                        },
+                    )| (filename, content),
+                )
+                .collect(),
+            revision: history.first().map(|h| h.version.clone()),
+            history: history
+                .into_iter()
+                .map(
+                    |GistHistoryEntry {
+                         version,
+                         committed_at,
+                         change_status,
+                     }| Revision {
+                        version,
+                        committed_at,
+                        additions: change_status.additions,
+                        deletions: change_status.deletions,
+                    },
+                )
+                .collect(),
+        })
+    }
+
+    fn create(
+        &self,
+        token: &str,
+        files: &IndexMap<String, String>,
+        description: &str,
+        public: bool,
+        dry_run: bool,
+    ) -> anyhow::Result<Option<Pushed>> {
+        let url = self.gists_url()?;
+
+        warn_limits(files);
+
+        if dry_run {
+            info!("[dry-run] POST {}", url);
+            return Ok(None);
+        }
+
+        let payload = json!({
+            "files": files
+                .iter()
+                .map(|(filename, content)| (filename.clone(), json!({ "content": content })))
+                .collect::<serde_json::Map<_, _>>(),
+            "description": description,
+            "public": public
+        });
+        self.send_files("POST", &url, token, &payload, 201)
+            .map(Some)
+    }
+
+    fn update(
+        &self,
+        token: &str,
+        gist_id: &str,
+        files: &IndexMap<String, String>,
+        removed: &[String],
+        rename: Option<(&str, &str)>,
+        description: Option<&str>,
+        dry_run: bool,
+    ) -> anyhow::Result<Option<Pushed>> {
+        let url = self.gist_url(gist_id)?;
+
+        warn_limits(files);
+
+        if dry_run {
+            info!("[dry-run] PATCH {}", url);
+            return Ok(None);
+        }
+
+        let files = files
+            .iter()
+            .map(|(filename, content)| match rename {
+                Some((from, to)) if to == filename => (
+                    from.to_owned(),
+                    json!({ "filename": to, "content": content }),
+                ),
+                _ => (filename.clone(), json!({ "content": content })),
+            })
+            // `null` deletes the file.
+            .chain(
+                removed
+                    .iter()
+                    .map(|filename| (filename.clone(), json!(null))),
+            )
+            .collect::<serde_json::Map<_, _>>();
+
+        let mut payload = json!({ "files": files });
+        if let Some(description) = description {
+            payload["description"] = json!(description);
+        }
+        self.send_files("PATCH", &url, token, &payload, 200)
+            .map(Some)
+    }
+
+    fn delete(&self, token: &str, gist_id: &str, dry_run: bool) -> anyhow::Result<()> {
+        let url = self.gist_url(gist_id)?;
+
+        if dry_run {
+            info!("[dry-run] DELETE {}", url);
+            return Ok(());
+        }
+
+        info!("DELETE {}", url);
+        self.http
+            .send_expecting("DELETE", &url, Some(token), None, 204)?;
+        Ok(())
+    }
+
+    fn list(&self, token: &str) -> anyhow::Result<Vec<Listing>> {
+        let mut gists = vec![];
+        for page in 1.. {
+            let mut url = self.gists_url()?;
+            url.query_pairs_mut()
+                .append_pair("per_page", &PER_PAGE.to_string())
+                .append_pair("page", &page.to_string());
+
+            info!("GET: {}", url);
+            let res = self
+                .http
+                .send_expecting("GET", &url, Some(token), None, 200)?;
+            let page = serde_json::from_str::<Vec<Gist>>(&res.into_string()?)?;
+            let is_last = page.len() < PER_PAGE;
+            gists.extend(page);
+            if is_last {
+                break;
+            }
+        }
+
+        return Ok(gists
+            .into_iter()
+            .map(
+                |Gist {
+                     id,
+                     description,
+                     public,
+                     files,
+                 }| Listing {
+                    id,
+                    description,
+                    public,
+                    files: files
+                        .into_iter()
+                        .map(|(_, f)| (f.filename, f.content))
+                        .collect(),
+                },
+            )
+            .collect());
+
+        const PER_PAGE: usize = 100;
+
+        #[derive(Deserialize)]
+        struct Gist {
+            id: String,
+            description: Option<String>,
+            public: bool,
+            files: IndexMap<String, GistFile>,
+        }
+
+        #[derive(Deserialize)]
+        struct GistFile {
+            filename: String,
+            /// Not included by GitHub itself.
+            #[serde(default)]
+            content: Option<String>,
+        }
+    }
+
+    /// - `https://api.github.com/` → `https://gist.github.com/<id>`
+    /// - `https://<host>/api/v3/` (GitHub Enterprise) → `https://<host>/gist/<id>`
+    /// - otherwise → `<base>/gist/<id>`
+    fn html_url(&self, gist_id: &str) -> anyhow::Result<Option<Url>> {
+        let mut html = self.base.clone();
+        if html.host_str() == Some("api.github.com") {
+            html.set_host(Some("gist.github.com"))?;
+            html.set_path("/");
+        } else if html.path().ends_with("/api/v3/") {
+            let path = html.path().trim_end_matches("api/v3/").to_owned();
+            html.set_path(&format!("{}gist/", path));
+        } else {
+            html = html.join("gist/")?;
+        }
+        Ok(Some(html.join(gist_id)?))
+    }
+}
+
+#[derive(Deserialize, Serialize)]
+struct CachedGist {
+    etag: Option<String>,
+    gist: Gist,
+}
+
+#[derive(Deserialize, Serialize)]
+struct Gist {
+    files: IndexMap<String, GistFile>,
+    #[serde(default)]
+    history: Vec<GistHistoryEntry>,
+    #[serde(default)]
+    truncated: bool,
+}
+
+#[derive(Deserialize, Serialize)]
+struct GistFile {
+    filename: String,
+    truncated: bool,
+    content: String,
+    size: u64,
+    raw_url: Url,
+}
+
+#[derive(Deserialize, Serialize)]
+struct GistHistoryEntry {
+    version: String,
+    #[serde(default)]
+    committed_at: String,
+    #[serde(default)]
+    change_status: GistChangeStatus,
+}
+
+#[derive(Default, Deserialize, Serialize)]
+struct GistChangeStatus {
+    #[serde(default)]
+    additions: u64,
+    #[serde(default)]
+    deletions: u64,
+}
+
+/// A broken cache is ignored.
+fn read_cache(path: &Path) -> Option<CachedGist> {
+    if !path.exists() {
+        return None;
+    }
+    crate::read(path)
+        .and_then(|json| serde_json::from_str(&json).map_err(Into::into))
+        .map_err(|err| warn!("Ignoring the cache at {}: {}", path.display(), err))
+        .ok()
+}
+
+fn write_cache(path: &Path, cached: &CachedGist) -> anyhow::Result<()> {
+    if let Some(parent) = path.parent() {
+        crate::create_dir_all(parent, false)?;
+    }
+    crate::write_private(path, serde_json::to_string(cached)?, false)
+}
+
+fn warn_limits(files: &IndexMap<String, String>) {
+    if files.len() > GIST_MAX_FILES {
+        warn!(
+            "Pushing {} files. The Gist API lists only the first {} files",
+            files.len(),
+            GIST_MAX_FILES,
+        );
+    }
+    for (filename, content) in files {
+        let size = content.len() as u64;
+        if size > GIST_RAW_FILE_SIZE_LIMIT {
+            warn!(
+                "{} is larger than {} bytes. It will not be retrievable via the API",
+                filename, GIST_RAW_FILE_SIZE_LIMIT,
+            );
+        } else if size > GIST_API_FILE_SIZE_LIMIT {
+            warn!(
+                "{} is larger than {} bytes. It will be truncated in the API responses",
+                filename, GIST_API_FILE_SIZE_LIMIT,
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Github;
+    use crate::http;
+    use crate::remote::Remote as _;
+
+    use std::fs;
+    use std::io::{BufRead as _, BufReader, Write as _};
+    use std::net::TcpListener;
+    use std::path::Path;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn cache_path() -> anyhow::Result<()> {
+        let github = |base: &str| -> anyhow::Result<_> {
+            Ok(Github::new(
+                base.parse()?,
+                client()?,
+                Some("/cache".into()),
+                false,
+            ))
+        };
+
+        let api = github("https://api.github.com")?;
+        assert_eq!(
+            api.cache_path("0123abcd", None),
+            Some(Path::new("/cache/api.github.com/0123abcd.json").to_owned()),
+        );
+        assert_eq!(
+            api.cache_path("0123abcd", Some("4567ef")),
+            Some(Path::new("/cache/api.github.com/0123abcd@4567ef.json").to_owned()),
+        );
+        assert_eq!(
+            github("http://127.0.0.1:8080/api/v3/")?.cache_path("0123abcd", None),
+            Some(Path::new("/cache/127.0.0.1_8080/0123abcd.json").to_owned()),
+        );
+
+        for (gist_id, revision) in &[
+            ("", None),
+            ("..", None),
+            ("../0123abcd", None),
+            ("a/b", None),
+            ("a\\b", None),
+            ("a.json", None),
+            ("0123abcd", Some("")),
+            ("0123abcd", Some("..")),
+            ("0123abcd", Some("a/b")),
+        ] {
+            assert_eq!(api.cache_path(gist_id, *revision), None, "{:?}", gist_id);
+        }
+
+        let no_cache = Github::new("https://api.github.com".parse()?, client()?, None, false);
+        assert_eq!(no_cache.cache_path("0123abcd", None), None);
+        Ok(())
+    }
+
+    #[test]
+    fn cache() -> anyhow::Result<()> {
+        let tempdir = tempfile::Builder::new()
+            .prefix("cargo-scripts-")
+            .tempdir()?;
+        let served = Arc::new(Mutex::new(gist("fn main() {}\n", "\"1\"")));
+        let requests = Arc::new(Mutex::new(vec![]));
+        let base = serve(served.clone(), requests.clone())?;
+        let github = |offline: bool| -> anyhow::Result<_> {
+            Ok(Github::new(
+                base.parse()?,
+                client()?,
+                Some(tempdir.path().to_owned()),
+                offline,
+            ))
+        };
+        let content = |offline: bool| -> anyhow::Result<_> {
+            Ok(github(offline)?.fetch(None, "abc", None)?.files["a.rs"].clone())
+        };
+
+        // Not cached yet.
+        assert!(github(true)?.fetch(None, "abc", None).is_err());
+
+        assert_eq!(content(false)?, "fn main() {}\n");
+        let cache_path = github(false)?.cache_path("abc", None).unwrap();
+        assert!(cache_path.exists());
+        assert_eq!(requests.lock().unwrap().last().unwrap(), &None);
+
+        // `304 Not Modified`.
+        assert_eq!(content(false)?, "fn main() {}\n");
+        assert_eq!(
+            requests.lock().unwrap().last().unwrap().as_deref(),
+            Some("\"1\""),
+        );
+        assert_eq!(content(true)?, "fn main() {}\n");
+        assert_eq!(requests.lock().unwrap().len(), 2);
+
+        *served.lock().unwrap() = gist("fn main() { println!(); }\n", "\"2\"");
+        assert_eq!(content(false)?, "fn main() { println!(); }\n");
+        assert_eq!(content(true)?, "fn main() { println!(); }\n");
+
+        // A broken cache is ignored.
+        fs::write(&cache_path, "{")?;
+        assert!(github(true)?.fetch(None, "abc", None).is_err());
+        assert_eq!(content(false)?, "fn main() { println!(); }\n");
+        assert_eq!(requests.lock().unwrap().last().unwrap(), &None);
+        Ok(())
+    }
+
+    /// `(ETag, body)`
+    fn gist(content: &str, etag: &str) -> (String, String) {
+        let body = serde_json::json!({
+            "files": {
+                "a.rs": {
+                    "filename": "a.rs",
+                    "truncated": false,
+                    "content": content,
+                    "size": content.len(),
+                    "raw_url": "https://gist.githubusercontent.com/a.rs",
+                },
+            },
+            "history": [{ "version": etag.trim_matches('"') }],
+        });
+        (etag.to_owned(), body.to_string())
+    }
+
+    fn client() -> anyhow::Result<http::Client> {
+        http::Client::new(
+            http::Service::Github,
+            Duration::from_secs(10),
+            Duration::from_secs(10),
+            None,
+            None,
+            0,
+        )
+    }
+
+    /// Serves `gist` with its ETag, or `304` for `If-None-Match` with the ETag. Records the
+    /// `If-None-Match` of each request.
+    fn serve(
+        gist: Arc<Mutex<(String, String)>>,
+        requests: Arc<Mutex<Vec<Option<String>>>>,
+    ) -> anyhow::Result<String> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let base = format!("http://{}/", listener.local_addr()?);
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut if_none_match = None;
+                let mut reader = BufReader::new(&stream);
+                loop {
+                    let mut line = "".to_owned();
+                    reader.read_line(&mut line).unwrap();
+                    if line.trim().is_empty() {
+                        break;
+                    }
+                    if let Some((name, value)) = line.split_once(':') {
+                        if name.eq_ignore_ascii_case("If-None-Match") {
+                            if_none_match = Some(value.trim().to_owned());
+                        }
+                    }
+                }
+                let (etag, body) = gist.lock().unwrap().clone();
+                let res = if if_none_match.as_ref() == Some(&etag) {
+                    "HTTP/1.1 304 Not Modified\r\nConnection: close\r\n\r\n".to_owned()
+                } else {
+                    format!(
+                        "HTTP/1.1 200 OK\r\nETag: {}\r\nContent-Length: {}\r\n\
+                         Connection: close\r\n\r\n{}",
+                        etag,
+                        body.len(),
+                        body,
+                    )
+                };
+                requests.lock().unwrap().push(if_none_match);
+                stream.write_all(res.as_ref()).unwrap();
+            }
+        });
+        Ok(base)
+    }
+}
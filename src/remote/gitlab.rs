@@ -0,0 +1,458 @@
+//! GitLab Snippets.
+//!
+//! Snippets are git repositories as gists are, but the API does not expose their commits. The
+//! revision of a snippet is a hash of its files, so only the latest one can be fetched.
+
+use super::{Listing, NotFound, Pushed, Remote, Snapshot};
+use crate::http;
+use indexmap::IndexMap;
+use log::info;
+use serde::Deserialize;
+use serde_json::json;
+use url::Url;
+
+#[derive(Debug)]
+pub(crate) struct Gitlab {
+    base: Url,
+    http: http::Client,
+}
+
+impl Gitlab {
+    pub(crate) fn new(base: Url, http: http::Client) -> Self {
+        let base = super::with_trailing_slash(base);
+        Self { base, http }
+    }
+
+    fn snippets_url(&self) -> anyhow::Result<Url> {
+        self.base.join("snippets").map_err(Into::into)
+    }
+
+    fn snippet_url(&self, snippet_id: &str) -> anyhow::Result<Url> {
+        self.base
+            .join(&format!("snippets/{}", snippet_id))
+            .map_err(Into::into)
+    }
+
+    fn user_url(&self) -> anyhow::Result<Url> {
+        self.base.join("user").map_err(Into::into)
+    }
+
+    fn raw_file_url(&self, snippet_id: &str, path: &str) -> anyhow::Result<Url> {
+        let mut url = self.snippet_url(snippet_id)?;
+        url.path_segments_mut()
+            .expect("`snippet_url` should have failed for a cannot-be-a-base URL")
+            .extend(&["files", "HEAD", path, "raw"]);
+        Ok(url)
+    }
+
+    fn get_snippet(&self, token: Option<&str>, snippet_id: &str) -> anyhow::Result<Snippet> {
+        let url = self.snippet_url(snippet_id)?;
+
+        info!("GET: {}", url);
+        let res = self.http.send_expecting("GET", &url, token, None, 200)?;
+        serde_json::from_str(&res.into_string()?).map_err(Into::into)
+    }
+
+    /// Sends `payload`, after which the snippet consists of `files`.
+    fn send_files(
+        &self,
+        method: &str,
+        url: &Url,
+        token: &str,
+        payload: &serde_json::Value,
+        files: &IndexMap<String, String>,
+        expected: u16,
+    ) -> anyhow::Result<Pushed> {
+        info!("{} {}", method, url);
+        let res = self
+            .http
+            .send_expecting(method, url, Some(token), Some(payload), expected)?;
+        let Snippet { id, .. } = serde_json::from_str(&res.into_string()?)?;
+        // The revision is of what has been sent. Fetching the snippet again could pick up an edit
+        // made in the meantime, which would then be taken as synced.
+        Ok(Pushed {
+            id: id.to_string(),
+            revision: Some(content_hash(files)),
+        })
+    }
+}
+
+impl Remote for Gitlab {
+    fn name(&self) -> &'static str {
+        "GitLab"
+    }
+
+    /// Checks that `GET /user` accepts `token`.
+    fn check_token(&self, token: &str) -> anyhow::Result<()> {
+        let url = self.user_url()?;
+
+        info!("GET: {}", url);
+        self.http
+            .send_expecting("GET", &url, Some(token), None, 200)?;
+        Ok(())
+    }
+
+    fn fetch(
+        &self,
+        token: Option<&str>,
+        snippet_id: &str,
+        revision: Option<&str>,
+    ) -> anyhow::Result<Snapshot> {
+        let Snippet { files, .. } = self.get_snippet(token, snippet_id)?;
+
+        let files = files
+            .into_iter()
+            .map(|SnippetFile { path }| {
+                let url = self.raw_file_url(snippet_id, &path)?;
+                info!("GET: {}", url);
+                let res = self.http.send_expecting("GET", &url, token, None, 200)?;
+                Ok((path, res.into_string()?))
+            })
+            .collect::<anyhow::Result<_>>()?;
+
+        let latest = content_hash(&files);
+        if let Some(revision) = revision.filter(|&r| r != latest) {
+            return Err(
+                anyhow::Error::new(NotFound(format!("{}@{}", snippet_id, revision)))
+                    .context("GitLab snippets only keep the latest revision"),
+            );
+        }
+
+        Ok(Snapshot {
+            files,
+            revision: Some(latest),
+            history: vec![],
+        })
+    }
+
+    fn create(
+        &self,
+        token: &str,
+        files: &IndexMap<String, String>,
+        description: &str,
+        public: bool,
+        dry_run: bool,
+    ) -> anyhow::Result<Option<Pushed>> {
+        let url = self.snippets_url()?;
+
+        if dry_run {
+            info!("[dry-run] POST {}", url);
+            return Ok(None);
+        }
+
+        let payload = json!({
+            // The title is required.
+            "title": files.keys().next(),
+            "description": description,
+            "visibility": if public { "public" } else { "private" },
+            "files": files
+                .iter()
+                .map(|(path, content)| json!({ "file_path": path, "content": content }))
+                .collect::<Vec<_>>()
+        });
+        self.send_files("POST", &url, token, &payload, files, 201)
+            .map(Some)
+    }
+
+    fn update(
+        &self,
+        token: &str,
+        snippet_id: &str,
+        files: &IndexMap<String, String>,
+        removed: &[String],
+        rename: Option<(&str, &str)>,
+        description: Option<&str>,
+        dry_run: bool,
+    ) -> anyhow::Result<Option<Pushed>> {
+        let url = self.snippet_url(snippet_id)?;
+
+        if dry_run {
+            info!("[dry-run] PUT {}", url);
+            return Ok(None);
+        }
+
+        // Each file must be marked as either "create" or "update".
+        let Snippet {
+            files: existing, ..
+        } = self.get_snippet(Some(token), snippet_id)?;
+        let exists = |path: &str| existing.iter().any(|f| f.path == path);
+
+        let actions = files
+            .iter()
+            .map(|(path, content)| match rename {
+                Some((from, to)) if to == path => json!({
+                    "action": "move",
+                    "previous_path": from,
+                    "file_path": to,
+                    "content": content
+                }),
+                _ => json!({
+                    "action": if exists(path) { "update" } else { "create" },
+                    "file_path": path,
+                    "content": content
+                }),
+            })
+            .chain(
+                removed
+                    .iter()
+                    .map(|path| json!({ "action": "delete", "file_path": path })),
+            )
+            .collect::<Vec<_>>();
+
+        let mut payload = json!({ "files": actions });
+        if let Some(description) = description {
+            payload["description"] = json!(description);
+        }
+        // `removed` contains every other file.
+        self.send_files("PUT", &url, token, &payload, files, 200)
+            .map(Some)
+    }
+
+    fn delete(&self, token: &str, snippet_id: &str, dry_run: bool) -> anyhow::Result<()> {
+        let url = self.snippet_url(snippet_id)?;
+
+        if dry_run {
+            info!("[dry-run] DELETE {}", url);
+            return Ok(());
+        }
+
+        info!("DELETE {}", url);
+        self.http
+            .send_expecting("DELETE", &url, Some(token), None, 204)?;
+        Ok(())
+    }
+
+    fn list(&self, token: &str) -> anyhow::Result<Vec<Listing>> {
+        let mut snippets = vec![];
+        for page in 1.. {
+            let mut url = self.snippets_url()?;
+            url.query_pairs_mut()
+                .append_pair("per_page", &PER_PAGE.to_string())
+                .append_pair("page", &page.to_string());
+
+            info!("GET: {}", url);
+            let res = self
+                .http
+                .send_expecting("GET", &url, Some(token), None, 200)?;
+            let page = serde_json::from_str::<Vec<Snippet>>(&res.into_string()?)?;
+            let is_last = page.len() < PER_PAGE;
+            snippets.extend(page);
+            if is_last {
+                break;
+            }
+        }
+
+        return Ok(snippets
+            .into_iter()
+            .map(
+                |Snippet {
+                     id,
+                     description,
+                     visibility,
+                     files,
+                 }| Listing {
+                    id: id.to_string(),
+                    description,
+                    public: visibility == "public",
+                    files: files.into_iter().map(|f| (f.path, None)).collect(),
+                },
+            )
+            .collect());
+
+        const PER_PAGE: usize = 100;
+    }
+
+    /// - `https://<host>/api/v4/` → `https://<host>/-/snippets/<id>`
+    /// - otherwise → `<base>/-/snippets/<id>`
+    fn html_url(&self, snippet_id: &str) -> anyhow::Result<Option<Url>> {
+        let mut html = self.base.clone();
+        if html.path().ends_with("/api/v4/") {
+            let path = html.path().trim_end_matches("api/v4/").to_owned();
+            html.set_path(&path);
+        }
+        Ok(Some(html.join(&format!("-/snippets/{}", snippet_id))?))
+    }
+}
+
+/// FNV-1a of the paths and the contents in the order of the paths.
+fn content_hash(files: &IndexMap<String, String>) -> String {
+    let mut hash = 0xcbf2_9ce4_8422_2325u64;
+    let mut write = |bytes: &[u8]| {
+        for &byte in (bytes.len() as u64).to_le_bytes().iter().chain(bytes) {
+            hash = (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3);
+        }
+    };
+    let mut files = files.iter().collect::<Vec<_>>();
+    files.sort();
+    for (path, content) in files {
+        write(path.as_ref());
+        write(content.as_ref());
+    }
+    format!("{:016x}", hash)
+}
+
+#[derive(Deserialize)]
+struct Snippet {
+    id: u64,
+    #[serde(default)]
+    description: Option<String>,
+    #[serde(default)]
+    visibility: String,
+    #[serde(default)]
+    files: Vec<SnippetFile>,
+}
+
+#[derive(Deserialize)]
+struct SnippetFile {
+    path: String,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{content_hash, Gitlab};
+    use crate::http;
+    use crate::remote::{is_not_found, Remote as _};
+    use indexmap::indexmap;
+
+    use std::collections::HashMap;
+    use std::io::{BufRead as _, BufReader, Read as _, Write as _};
+    use std::net::TcpListener;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn revision() -> anyhow::Result<()> {
+        let responses = Arc::new(Mutex::new(HashMap::new()));
+        let base = serve(responses.clone())?;
+        let set = |main_rs: &str| {
+            let mut responses = responses.lock().unwrap();
+            responses.insert(
+                "/snippets/1".to_owned(),
+                r#"{"id":1,"files":[{"path":"main.rs"},{"path":"Cargo.toml"}]}"#.to_owned(),
+            );
+            responses.insert(
+                "/snippets/1/files/HEAD/main.rs/raw".to_owned(),
+                main_rs.to_owned(),
+            );
+            responses.insert(
+                "/snippets/1/files/HEAD/Cargo.toml/raw".to_owned(),
+                "[package]\n".to_owned(),
+            );
+        };
+        let gitlab = Gitlab::new(base.parse()?, client()?);
+
+        set("fn main() {}\n");
+        let first = gitlab.fetch(None, "1", None)?.revision.unwrap();
+        assert_eq!(
+            gitlab.fetch(None, "1", None)?.revision.as_ref(),
+            Some(&first)
+        );
+        assert_eq!(
+            gitlab.fetch(None, "1", Some(&first))?.files["main.rs"],
+            "fn main() {}\n",
+        );
+
+        set("fn main() { println!(); }\n");
+        let second = gitlab.fetch(None, "1", None)?.revision.unwrap();
+        assert_ne!(first, second);
+        assert!(is_not_found(
+            &gitlab.fetch(None, "1", Some(&first)).unwrap_err()
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn update_records_the_sent_files() -> anyhow::Result<()> {
+        let responses = Arc::new(Mutex::new(HashMap::new()));
+        let base = serve(responses.clone())?;
+        {
+            // Someone has edited the snippet right after the push.
+            let mut responses = responses.lock().unwrap();
+            responses.insert(
+                "/snippets/1".to_owned(),
+                r#"{"id":1,"files":[{"path":"main.rs"}]}"#.to_owned(),
+            );
+            responses.insert(
+                "/snippets/1/files/HEAD/main.rs/raw".to_owned(),
+                "fn main() { println!(); }\n".to_owned(),
+            );
+        }
+        let gitlab = Gitlab::new(base.parse()?, client()?);
+
+        let files = indexmap!("main.rs".to_owned() => "fn main() {}\n".to_owned());
+        let pushed = gitlab
+            .update("token", "1", &files, &[], None, None, false)?
+            .unwrap();
+        assert_eq!(pushed.id, "1");
+        assert_eq!(pushed.revision, Some(content_hash(&files)));
+        assert_ne!(pushed.revision, gitlab.fetch(None, "1", None)?.revision);
+        Ok(())
+    }
+
+    #[test]
+    fn update_dry_run_does_not_send_requests() -> anyhow::Result<()> {
+        // Nothing listens on the port.
+        let port = TcpListener::bind("127.0.0.1:0")?.local_addr()?.port();
+        let gitlab = Gitlab::new(format!("http://127.0.0.1:{}/", port).parse()?, client()?);
+        let files = indexmap!("main.rs".to_owned() => "fn main() {}\n".to_owned());
+        assert!(gitlab
+            .update("token", "1", &files, &[], None, None, true)?
+            .is_none());
+        Ok(())
+    }
+
+    fn client() -> anyhow::Result<http::Client> {
+        http::Client::new(
+            http::Service::Gitlab,
+            Duration::from_secs(10),
+            Duration::from_secs(10),
+            None,
+            None,
+            0,
+        )
+    }
+
+    /// Serves `responses` (path → body) on a local port and returns the base URL.
+    fn serve(responses: Arc<Mutex<HashMap<String, String>>>) -> anyhow::Result<String> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let base = format!("http://{}/", listener.local_addr()?);
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut request_line = "".to_owned();
+                let mut reader = BufReader::new(&stream);
+                reader.read_line(&mut request_line).unwrap();
+                let mut content_length = 0;
+                loop {
+                    let mut header = "".to_owned();
+                    reader.read_line(&mut header).unwrap();
+                    if header.trim().is_empty() {
+                        break;
+                    }
+                    let (name, value) = header.split_once(':').unwrap_or_default();
+                    if name.eq_ignore_ascii_case("content-length") {
+                        content_length = value.trim().parse().unwrap();
+                    }
+                }
+                // Closing the connection with the request body unread would reset it.
+                reader.read_exact(&mut vec![0; content_length]).unwrap();
+                let path = request_line.split(' ').nth(1).unwrap_or_default();
+                let body = responses.lock().unwrap().get(path).cloned();
+                let (status, body) = match body {
+                    Some(body) => ("200 OK", body),
+                    None => ("404 Not Found", r#"{"message":"404 Not Found"}"#.to_owned()),
+                };
+                write!(
+                    stream,
+                    "HTTP/1.1 {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    status,
+                    body.len(),
+                    body,
+                )
+                .unwrap();
+            }
+        });
+        Ok(base)
+    }
+}
@@ -0,0 +1,11 @@
+#![allow(dead_code)]
+    //! ```cargo
+    //! [package]
+    //! name = "doc-comment-attrs"
+    //! version = "0.0.0"
+    //! edition = "2018"
+    //! ```
+#![doc = "An attribute is left as it is."]
+
+/// An outer doc comment.
+fn main() {}
@@ -0,0 +1,13 @@
+/*!
+```cargo
+[package]
+name = "doc-comment-block"
+version = "0.0.0"
+edition = "2018"
+
+[dependencies]
+serde = { version = "1", features = ["derive"] }
+```
+*/
+
+fn main() {}
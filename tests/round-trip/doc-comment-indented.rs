@@ -0,0 +1,14 @@
+//!   A doc comment indented with three spaces.
+//!
+//!   ```cargo
+//!   [package]
+//!   name = "doc-comment-indented"
+//!   version = "0.0.0"
+//!   authors = [
+//!       "Alice <alice@example.com>",
+//!       "Bob <bob@example.com>",
+//!   ]
+//!   edition = "2018"
+//!   ```
+
+fn main() {}
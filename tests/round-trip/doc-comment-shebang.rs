@@ -0,0 +1,19 @@
+#!/usr/bin/env run-cargo-script
+//! Prints the arguments.
+//!
+//! ```cargo
+//! [package]
+//! name = "doc-comment-shebang"
+//! version = "0.0.0"
+//! edition = "2018"
+//! ```
+//!
+//! Run it with `cargo script`.
+
+#![warn(rust_2018_idioms)]
+
+fn main() {
+    for arg in std::env::args().skip(1) {
+        println!("{}", arg);
+    }
+}
@@ -0,0 +1,15 @@
+//! ```cargo
+//! [package]
+//! name = "doc-comment"
+//! version = "0.0.0"
+//! edition = "2018"
+//!
+//! [dependencies]
+//! itertools = "0.9.0"
+//! ```
+
+use itertools::Itertools as _;
+
+fn main() {
+    println!("{}", (1..=3).format(", "));
+}
@@ -0,0 +1,13 @@
+#!/usr/bin/env -S cargo +nightly -Zscript
+-----cargo
+[package]
+name = "frontmatter-long"
+version = "0.0.0"
+edition = "2024"
+description = """
+A manifest with a line of dashes
+---
+"""
+-----
+
+fn main() {}
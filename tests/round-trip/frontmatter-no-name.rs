@@ -0,0 +1,9 @@
+---
+[package] # no `name`
+edition = "2024"
+
+[dependencies]
+regex = "1"
+---
+
+fn main() {}
@@ -0,0 +1,7 @@
+#!/usr/bin/env -S cargo +nightly -Zscript
+---cargo
+[dependencies]
+regex = "1"
+---
+
+fn main() {}
@@ -0,0 +1,11 @@
+---
+[package]
+name = "frontmatter-plain"
+version = "0.0.0"
+edition = "2024"
+
+[dependencies]
+regex = "1"
+---
+
+fn main() {}
@@ -0,0 +1,12 @@
+#!/usr/bin/env -S cargo +nightly -Zscript
+---cargo
+[package]
+name = "frontmatter"
+version = "0.0.0"
+edition = "2024"
+
+[dependencies]
+regex = "1"
+---
+
+fn main() {}
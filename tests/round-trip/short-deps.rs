@@ -0,0 +1,3 @@
+// cargo-deps: itertools="0.9.0", regex="1"
+
+fn main() {}